@@ -0,0 +1,135 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use toml::Table;
+
+use crate::config::Config;
+use crate::gallery::Gallery;
+use crate::{App, ctx};
+
+/// Lists of blacklisted names, in the `[blacklist]` table of the config file or in the
+/// blacklist file
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct Lists {
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    artists: Vec<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+    #[serde(default)]
+    parodies: Vec<String>,
+    #[serde(default)]
+    characters: Vec<String>,
+    #[serde(default)]
+    languages: Vec<String>,
+    #[serde(default)]
+    categories: Vec<String>,
+}
+
+impl Lists {
+    /// Each list with the tag kind it applies to and its key in the file
+    fn kinds(self) -> [(&'static str, &'static str, Vec<String>); 7] {
+        [
+            ("tag", "tags", self.tags),
+            ("artist", "artists", self.artists),
+            ("group", "groups", self.groups),
+            ("parody", "parodies", self.parodies),
+            ("character", "characters", self.characters),
+            ("language", "languages", self.languages),
+            ("category", "categories", self.categories),
+        ]
+    }
+}
+
+struct Entry {
+    kind: &'static str,
+    name: String,
+    /// Where the entry was defined, shown when explaining a match
+    source: String,
+}
+
+/// Tags, artists, groups, ... that are never downloaded, whatever the mode
+#[derive(Default)]
+pub struct Blacklist {
+    entries: Vec<Entry>,
+}
+
+/// Default location of the blacklist file (e.g. `~/.config/nhentai-downloader/blacklist.toml`)
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("nhentai-downloader").join("blacklist.toml"))
+}
+
+fn normalize(name: &str) -> String {
+    name.trim().to_lowercase().replace('-', " ")
+}
+
+impl Blacklist {
+    fn add(&mut self, lists: Lists, source: impl Fn(&str) -> String) {
+        for (kind, key, names) in lists.kinds() {
+            let source = source(key);
+            self.entries.extend(names.iter().map(|name| Entry {
+                kind,
+                name: normalize(name),
+                source: source.clone(),
+            }));
+        }
+    }
+
+    /// Loads the blacklist from the config file and from the blacklist file, a missing file is
+    /// only an error when its path was given explicitly
+    pub fn load(config: &Config, file: Option<&Path>) -> Result<Self> {
+        let mut blacklist = Self::default();
+
+        if let Some(table) = config.data_section("blacklist") {
+            let lists: Lists = Table::try_into(table.clone())
+                .with_context(ctx!("Invalid `[blacklist]` table in config file"))?;
+            blacklist.add(lists, |key| config.describe(&format!("blacklist.{key}")));
+        }
+
+        let (path, explicit) = match file {
+            Some(path) => (Some(path.to_path_buf()), true),
+            None => (default_path(), false),
+        };
+        let Some(path) = path else {
+            return Ok(blacklist);
+        };
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if !explicit && e.kind() == ErrorKind::NotFound => return Ok(blacklist),
+            Err(e) => return Err(e).with_context(ctx!("Cannot read blacklist file {path:?}")),
+        };
+        let lists: Lists = toml::from_str(&text)
+            .with_context(ctx!("Cannot parse blacklist file {path:?}"))?;
+        blacklist.add(lists, |_| format!("blacklist file {path:?}"));
+
+        Ok(blacklist)
+    }
+
+    /// Finds the entries that exclude the gallery, as `kind:name (source)` descriptions
+    pub fn check(&self, gallery: &Gallery) -> Vec<String> {
+        self.entries.iter()
+            .filter(|e| gallery.tags.iter().any(|t| t.kind == e.kind && normalize(&t.name) == e.name))
+            .map(|e| format!("{}:\"{}\" ({})", e.kind, e.name, e.source))
+            .collect()
+    }
+}
+
+impl App {
+    pub async fn test_blacklist(&self, id: u32) -> Result<()> {
+        let gallery = self.load_gallery(id).await?;
+        let matches = self.blacklist.check(&gallery);
+        if matches.is_empty() {
+            println!("{id} [{}] is not blacklisted", gallery.title.pretty);
+        } else {
+            println!("{id} [{}] is blacklisted by:", gallery.title.pretty);
+            for reason in matches {
+                println!("  {reason}");
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,341 @@
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use rusqlite::{Connection, OpenFlags, OptionalExtension, params};
+
+use crate::export::ListWriter;
+use crate::gallery::Gallery;
+use crate::query::{Listing, QueryInfo, QueryResult};
+use crate::{App, CatalogAction, CatalogCli, PageRangeCli, SearchCli, ctx, http, snapshot};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS galleries (
+        id INTEGER PRIMARY KEY,
+        media_id TEXT NOT NULL,
+        title_english TEXT NOT NULL,
+        title_japanese TEXT NOT NULL,
+        title_pretty TEXT NOT NULL,
+        pages INTEGER NOT NULL,
+        favorites INTEGER NOT NULL,
+        uploaded INTEGER NOT NULL,
+        -- Gallery info in the gallery.json format
+        info TEXT NOT NULL,
+        fetched INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS tags (
+        id INTEGER PRIMARY KEY,
+        type TEXT NOT NULL,
+        name TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS gallery_tags (
+        gallery INTEGER NOT NULL REFERENCES galleries(id) ON DELETE CASCADE,
+        tag INTEGER NOT NULL REFERENCES tags(id),
+        PRIMARY KEY (gallery, tag)
+    );
+    CREATE INDEX IF NOT EXISTS gallery_tags_tag ON gallery_tags(tag);
+    -- Ids that don't exist on the site
+    CREATE TABLE IF NOT EXISTS missing (
+        id INTEGER PRIMARY KEY,
+        fetched INTEGER NOT NULL
+    );
+";
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/// Local database with the info of galleries, without their images
+pub struct Catalog {
+    conn: Connection,
+}
+
+/// Number of entries of each kind in the catalog
+pub struct Stats {
+    pub galleries: usize,
+    pub tags: usize,
+    pub missing: usize,
+    pub oldest_fetch: Option<u64>,
+}
+
+impl Catalog {
+    /// Default location of the database (e.g. `~/.local/share/nhentai-downloader/catalog.sqlite`)
+    pub fn default_path() -> Option<PathBuf> {
+        crate::config::data_dir().map(|dir| dir.join("catalog.sqlite"))
+    }
+
+    /// Opens the database, creating it if needed
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(ctx!("Cannot create directory {parent:?}"))?;
+        }
+        let conn = Connection::open(path)
+            .with_context(ctx!("Cannot open catalog {path:?}"))?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")
+            .and_then(|_| conn.execute_batch(SCHEMA))
+            .with_context(ctx!("Cannot create the tables of catalog {path:?}"))?;
+        Ok(Self { conn })
+    }
+
+    /// Opens the database without writing to it, for dry runs. When it doesn't exist an empty
+    /// database in memory is used.
+    pub fn open_read_only(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            let conn = Connection::open_in_memory()
+                .and_then(|conn| conn.execute_batch(SCHEMA).map(|_| conn))
+                .with_context(ctx!("Cannot create an empty catalog"))?;
+            return Ok(Self { conn });
+        }
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)
+            .with_context(ctx!("Cannot open catalog {path:?}"))?;
+        Ok(Self { conn })
+    }
+
+    /// Checks if a gallery (or its absence from the site) was fetched less than `max_age` seconds
+    /// ago, or at all when `max_age` is `None`
+    pub fn is_fresh(&self, id: u32, max_age: Option<u64>) -> Result<bool> {
+        let fetched: Option<u64> = self.conn
+            .query_row(
+                "SELECT fetched FROM galleries WHERE id = ?1 UNION SELECT fetched FROM missing WHERE id = ?1",
+                params![id],
+                |row| row.get(0),
+            )
+            .optional()
+            .with_context(ctx!("Cannot read gallery {id} from the catalog"))?;
+        Ok(match (fetched, max_age) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(fetched), Some(max_age)) => now().saturating_sub(fetched) < max_age,
+        })
+    }
+
+    /// Adds or replaces the info of a gallery
+    pub fn insert(&mut self, gallery: &Gallery) -> Result<()> {
+        let id = gallery.id;
+        let info = serde_json::to_string(gallery)
+            .with_context(ctx!("Cannot serialize gallery {id}"))?;
+
+        let tx = self.conn.transaction()
+            .with_context(ctx!("Cannot write gallery {id} to the catalog"))?;
+        tx.execute(
+            "INSERT OR REPLACE INTO galleries
+                (id, media_id, title_english, title_japanese, title_pretty, pages, favorites, uploaded, info, fetched)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            params![
+                id, gallery.media_id, gallery.title.english, gallery.title.japanese, gallery.title.pretty,
+                gallery.pages(), gallery.num_favorites, gallery.upload_date, info, now(),
+            ],
+        )
+        .and_then(|_| tx.execute("DELETE FROM gallery_tags WHERE gallery = ?1", params![id]))
+        .and_then(|_| tx.execute("DELETE FROM missing WHERE id = ?1", params![id]))
+        .with_context(ctx!("Cannot write gallery {id} to the catalog"))?;
+
+        for tag in &gallery.tags {
+            tx.execute(
+                "INSERT OR REPLACE INTO tags (id, type, name) VALUES (?1, ?2, ?3)",
+                params![tag.id, tag.kind, tag.name],
+            )
+            .and_then(|_| tx.execute(
+                "INSERT OR IGNORE INTO gallery_tags (gallery, tag) VALUES (?1, ?2)",
+                params![id, tag.id],
+            ))
+            .with_context(ctx!("Cannot write tag `{}` of gallery {id} to the catalog", tag.name))?;
+        }
+
+        tx.commit()
+            .with_context(ctx!("Cannot write gallery {id} to the catalog"))
+    }
+
+    /// Records that a gallery doesn't exist on the site
+    pub fn mark_missing(&self, id: u32) -> Result<()> {
+        self.conn
+            .execute("INSERT OR REPLACE INTO missing (id, fetched) VALUES (?1, ?2)", params![id, now()])
+            .with_context(ctx!("Cannot write gallery {id} to the catalog"))?;
+        Ok(())
+    }
+
+    /// Info of all the galleries of the catalog
+    pub fn galleries(&self) -> Result<Vec<Gallery>> {
+        let mut stmt = self.conn.prepare("SELECT id, info FROM galleries ORDER BY id")
+            .with_context(ctx!("Cannot read the catalog"))?;
+        let rows = stmt.query_map([], |row| Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?)))
+            .with_context(ctx!("Cannot read the catalog"))?;
+
+        let mut galleries = Vec::new();
+        for row in rows {
+            let (id, info) = row.with_context(ctx!("Cannot read the catalog"))?;
+            match serde_json::from_str(&info) {
+                Ok(gallery) => galleries.push(gallery),
+                Err(e) => log::warn!("Invalid info for gallery {id} in the catalog\nError: {e}"),
+            }
+        }
+        Ok(galleries)
+    }
+
+    pub fn stats(&self) -> Result<Stats> {
+        let count = |table: &str| -> Result<usize> {
+            self.conn.query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| row.get(0))
+                .with_context(ctx!("Cannot read the catalog"))
+        };
+        Ok(Stats {
+            galleries: count("galleries")?,
+            tags: count("tags")?,
+            missing: count("missing")?,
+            oldest_fetch: self.conn.query_row("SELECT MIN(fetched) FROM galleries", [], |row| row.get(0))
+                .with_context(ctx!("Cannot read the catalog"))?,
+        })
+    }
+}
+
+impl App {
+    pub async fn update_catalog(&self, catalog: &CatalogCli) -> Result<()> {
+        let path = catalog.db.path()?;
+        let mut db = match self.args.dry_run {
+            true => Catalog::open_read_only(&path)?,
+            false => Catalog::open(&path)?,
+        };
+
+        let (query, sort, first_page, count, refresh) = match catalog.action {
+            CatalogAction::Stats => {
+                let stats = db.stats()?;
+                println!("{}: {} galleries, {} tags, {} missing ids", path.display(), stats.galleries, stats.tags, stats.missing);
+                if let Some(oldest) = stats.oldest_fetch {
+                    println!("Oldest entry fetched {}", snapshot::format_age(oldest));
+                }
+                return Ok(());
+            }
+            CatalogAction::Ids { ref ranges, ref refresh } => {
+                let ids: Vec<u32> = ranges.iter().flat_map(|r| r.clone()).collect();
+                return self.catalog_galleries(&mut db, &ids, refresh.refresh).await;
+            }
+            CatalogAction::Query { ref query, sort, first_page, count, ref refresh } => {
+                (query, sort, first_page, count, refresh.refresh)
+            }
+        };
+
+        let listing = Listing::Search { query: query.clone(), sort };
+        let query_res = QueryInfo::load(&self.client, &self.args.network.site_url, listing, first_page).await
+            .with_context(ctx!("Failed to load query `{query}`"))?;
+        let (query_info, galleries) = match query_res {
+            QueryResult::Gallery(id) => return self.catalog_galleries(&mut db, &[id], refresh).await,
+            QueryResult::QueryList(i, g) => (i, g),
+        };
+
+        let range = PageRangeCli {
+            first_page,
+            last_page: None,
+            count: Some(count),
+            until_known: false,
+            known_run: NonZeroUsize::MIN,
+            reverse: false,
+            max_galleries: None,
+        };
+        let last_page = range.last_page(query_info.pages(), true);
+        log::info!("Found {} pages available for {}", query_info.pages(), query_info.listing());
+
+        let mut galleries = Some(galleries);
+        for page in first_page.get()..=last_page.get() {
+            // SAFETY: None of the numbers between two non-zero numbers are zero.
+            let page = unsafe { NonZeroU32::new_unchecked(page) };
+            let galleries = match galleries.take() {
+                Some(g) => g,
+                None => match query_info.load_page(&self.client, page).await {
+                    Ok(g) => g,
+                    Err(e) if http::is_blocked(&e) => return Err(e),
+                    Err(e) => {
+                        log::warn!("Failed to download query page: {page}\nError: {e:?}");
+                        self.summary.failed_page();
+                        continue;
+                    }
+                },
+            };
+            log::info!(">>> ({page}/{last_page}) Cataloguing query page #{page}");
+            self.catalog_galleries(&mut db, &galleries, refresh).await?;
+        }
+        Ok(())
+    }
+
+    /// Adds the info of the galleries to the catalog, skipping the ones fetched less than `refresh`
+    /// seconds ago (or at all if `None`)
+    async fn catalog_galleries(&self, db: &mut Catalog, ids: &[u32], refresh: Option<u64>) -> Result<()> {
+        let (mut added, mut skipped, mut missing, mut failed) = (0, 0, 0, 0);
+        let count = ids.len();
+        for (i, &id) in ids.iter().enumerate() {
+            if db.is_fresh(id, refresh)? {
+                log::trace!("Gallery {id} is already in the catalog");
+                self.summary.present();
+                skipped += 1;
+                continue;
+            }
+            match self.load_gallery(id).await {
+                Ok(gallery) if self.args.dry_run => {
+                    log::info!("({}/{count}) Would catalogue gallery: {id} [{}]", i + 1, gallery.title.pretty);
+                    added += 1;
+                }
+                Ok(gallery) => {
+                    db.insert(&gallery)?;
+                    log::info!("({}/{count}) Catalogued gallery: {id} [{}]", i + 1, gallery.title.pretty);
+                    self.summary.downloaded();
+                    added += 1;
+                }
+                Err(e) if http::is_blocked(&e) => return Err(e),
+                Err(e) if http::is_not_found(&e) => {
+                    log::info!("({}/{count}) Gallery {id} doesn't exist", i + 1);
+                    if !self.args.dry_run {
+                        db.mark_missing(id)?;
+                    }
+                    missing += 1;
+                }
+                Err(e) => {
+                    log::warn!("Failed to load gallery: {id}\nError: {e:?}");
+                    self.summary.failed();
+                    failed += 1;
+                }
+            }
+        }
+        let verb = match self.args.dry_run {
+            true => "would be added",
+            false => "added",
+        };
+        log::info!("Catalog: {added} galleries {verb}, {skipped} already present, {missing} missing, {failed} failed");
+        Ok(())
+    }
+
+    pub fn search(&self, search: &SearchCli) -> Result<()> {
+        let mut galleries = std::collections::BTreeMap::new();
+        if !search.catalog_only {
+            for gallery in Gallery::read_library(self.out_path()?)? {
+                galleries.insert(gallery.id, gallery);
+            }
+        }
+        if !search.library_only {
+            let path = search.db.path()?;
+            if path.exists() {
+                for gallery in Catalog::open(&path)?.galleries()? {
+                    galleries.entry(gallery.id).or_insert(gallery);
+                }
+            } else if search.catalog_only {
+                anyhow::bail!("There is no catalog at {path:?}, create it with `catalog`");
+            }
+        }
+
+        let untyped = galleries.values().filter(|g| g.has_untyped_tags()).count();
+        if untyped > 0 {
+            log::warn!(
+                "{untyped} galleries were saved by an older version without the types of their tags, \
+                tag searches can't match them until their info is fetched again with `refresh`"
+            );
+        }
+
+        let mut writer = ListWriter::new(search.list_format, search.list_file.as_deref(), true)?;
+        let mut found = 0;
+        for gallery in galleries.values().filter(|g| search.expr.check(g).is_ok()) {
+            writer.write(gallery.id, Some(gallery))?;
+            found += 1;
+        }
+        log::info!("Found {found} matching galleries out of {}", galleries.len());
+        writer.finish()
+    }
+}
@@ -0,0 +1,597 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use toml::{Table, Value};
+
+use crate::ctx;
+
+/// Prefix of the environment variables that can set the options
+const ENV_PREFIX: &str = "NHENTAI";
+
+/// Tables of the config file that are not command line options
+const DATA_SECTIONS: &[&str] = &["blacklist"];
+
+/// Arguments that can't be set from the config file
+const IGNORED_ARGS: &[&str] = &["help", "version", "config", "profile"];
+
+/// Help heading of the generated `--no-<flag>` options
+const NEGATIONS_HEADING: &str = "Turn off flags set by the config file or the environment";
+
+/// Where a value of the effective configuration comes from
+#[derive(Clone, Debug)]
+enum Source {
+    File,
+    Profile(String),
+}
+
+/// Options loaded from the config file, with the selected profile applied on top
+#[derive(Default)]
+pub struct Config {
+    /// Path of the loaded config file, if any
+    pub path: Option<PathBuf>,
+    pub profile: Option<String>,
+    /// Options for the main command
+    values: Table,
+    /// Options for each subcommand
+    sections: HashMap<String, Table>,
+    /// Tables listed in [`DATA_SECTIONS`]
+    data: HashMap<String, Table>,
+    sources: HashMap<String, Source>,
+}
+
+/// Default location of the config file (e.g. `~/.config/nhentai-downloader/config.toml`)
+pub fn default_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("nhentai-downloader").join("config.toml"))
+}
+
+/// Directory for the files the program keeps between runs (e.g. `~/.local/share/nhentai-downloader`)
+pub fn data_dir() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("nhentai-downloader"))
+}
+
+fn normalize(key: &str) -> String {
+    key.replace('-', "_")
+}
+
+fn env_name(section: Option<&str>, id: &str) -> String {
+    match section {
+        Some(section) => format!("{ENV_PREFIX}_{section}_{id}"),
+        None => format!("{ENV_PREFIX}_{id}"),
+    }
+    .replace('-', "_")
+    .to_uppercase()
+}
+
+fn is_configurable(arg: &Arg) -> bool {
+    !arg.is_positional()
+        && !IGNORED_ARGS.contains(&arg.get_id().as_str())
+        && arg.get_help_heading() != Some(NEGATIONS_HEADING)
+}
+
+/// Id and long name of the option that turns off a flag (e.g. `no_overwrite` and `--no-overwrite`,
+/// or `check_missing_pages` for `--no-check-missing-pages`)
+fn negation(arg: &Arg) -> (String, String) {
+    let id = arg.get_id().as_str();
+    let long = arg.get_long().unwrap_or(id).to_string();
+    match (id.strip_prefix("no_"), long.strip_prefix("no-")) {
+        (Some(id), Some(long)) => (id.to_string(), long.to_string()),
+        _ => (format!("no_{id}"), format!("no-{long}")),
+    }
+}
+
+/// Key of an option in [`Config::sources`]
+fn key(section: Option<&str>, id: &str) -> String {
+    match section {
+        Some(section) => format!("{section}.{id}"),
+        None => id.to_string(),
+    }
+}
+
+fn value_to_strings(key: &str, value: &Value) -> Result<Vec<String>> {
+    match value {
+        Value::String(s) => Ok(vec![s.clone()]),
+        Value::Integer(i) => Ok(vec![i.to_string()]),
+        Value::Float(f) => Ok(vec![f.to_string()]),
+        Value::Boolean(b) => Ok(vec![b.to_string()]),
+        Value::Array(values) => values.iter()
+            .map(|v| match v {
+                Value::Array(_) | Value::Table(_) => anyhow::bail!("Nested values are not allowed in `{key}`"),
+                v => value_to_strings(key, v).map(|mut v| v.remove(0)),
+            })
+            .collect(),
+        Value::Datetime(d) => Ok(vec![d.to_string()]),
+        Value::Table(_) => anyhow::bail!("Unexpected table `{key}`"),
+    }
+}
+
+impl Config {
+    /// Loads the config file, a missing file is only an error when its path was given explicitly
+    pub fn load(path: Option<&Path>, profile: Option<&str>) -> Result<Self> {
+        let (path, explicit) = match path {
+            Some(path) => (Some(path.to_path_buf()), true),
+            None => (default_path(), false),
+        };
+
+        let text = match path {
+            Some(ref path) => match std::fs::read_to_string(path) {
+                Ok(text) => Some(text),
+                Err(e) if !explicit && e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e).with_context(ctx!("Cannot read config file {path:?}")),
+            },
+            None => None,
+        };
+
+        let Some(text) = text else {
+            if let Some(profile) = profile {
+                anyhow::bail!("Profile `{profile}` selected, but there is no config file");
+            }
+            return Ok(Self::default());
+        };
+        Self::parse(&text, path, profile)
+    }
+
+    /// Reads the text of the config file loaded from `path`
+    fn parse(text: &str, path: Option<PathBuf>, profile: Option<&str>) -> Result<Self> {
+        let mut table: Table = toml::from_str(text)
+            .with_context(ctx!("Cannot parse config file {path:?}"))?;
+
+        let mut config = Self { path, profile: profile.map(str::to_string), ..Default::default() };
+
+        let mut profiles = match table.remove("profile") {
+            Some(Value::Table(t)) => t,
+            Some(_) => anyhow::bail!("`profile` must be a table of profiles"),
+            None => Table::new(),
+        };
+
+        config.merge(table, Source::File);
+
+        if let Some(name) = profile {
+            let Some(Value::Table(profile)) = profiles.remove(name) else {
+                let available: Vec<_> = profiles.keys().map(String::as_str).collect();
+                anyhow::bail!("Profile `{name}` not found in config file, available profiles: {available:?}");
+            };
+            config.merge(profile, Source::Profile(name.to_string()));
+        }
+
+        Ok(config)
+    }
+
+    fn merge(&mut self, table: Table, source: Source) {
+        for (key, value) in table {
+            let key = normalize(&key);
+            match value {
+                Value::Table(section) => {
+                    let entry = match DATA_SECTIONS.contains(&key.as_str()) {
+                        true => self.data.entry(key.clone()).or_default(),
+                        false => self.sections.entry(key.clone()).or_default(),
+                    };
+                    for (sub_key, value) in section {
+                        let sub_key = normalize(&sub_key);
+                        self.sources.insert(format!("{key}.{sub_key}"), source.clone());
+                        entry.insert(sub_key, value);
+                    }
+                }
+                value => {
+                    self.sources.insert(key.clone(), source.clone());
+                    self.values.insert(key, value);
+                }
+            }
+        }
+    }
+
+    /// Adds env and config defaults to the options of a command, `negated` are the keys of the flags
+    /// turned off on the command line
+    fn apply_args(mut cmd: Command, section: Option<&str>, values: &Table, negated: &[String]) -> Result<Command> {
+        let args: Vec<_> = cmd.get_arguments()
+            .filter(|a| is_configurable(a))
+            .map(|a| a.get_id().to_string())
+            .collect();
+
+        for key in values.keys() {
+            if !args.contains(key) {
+                match section {
+                    Some(section) => anyhow::bail!("Unknown option `{key}` in section `[{section}]`"),
+                    None => anyhow::bail!("Unknown option `{key}`"),
+                }
+            }
+        }
+
+        for id in args {
+            let is_flag = cmd.get_arguments()
+                .any(|a| a.get_id() == id.as_str() && matches!(a.get_action(), ArgAction::SetTrue));
+            let env = env_name(section, &id);
+            // A flag from the environment can be false, which clap sees as the flag being absent
+            let overridden = is_flag
+                && (negated.contains(&key(section, &id)) || std::env::var_os(&env).is_some());
+
+            let defaults = match values.get(&id) {
+                Some(_) if overridden => None,
+                Some(value) => Some(value_to_strings(&id, value)?),
+                None => None,
+            };
+
+            if is_flag {
+                let flag = cmd.get_arguments().find(|a| a.get_id() == id.as_str()).unwrap();
+                let long = flag.get_long().unwrap_or(&id).to_string();
+                let (negation_id, negation_long) = negation(flag);
+                cmd = cmd.arg(
+                    Arg::new(negation_id)
+                        .long(negation_long)
+                        .action(ArgAction::SetTrue)
+                        .overrides_with(&id)
+                        .help(format!("Turn off `--{long}`"))
+                        .help_heading(NEGATIONS_HEADING)
+                        .hide_short_help(true),
+                );
+            }
+
+            cmd = cmd.mut_arg(&id, |mut arg| {
+                arg = arg.env(env);
+                if let Some(defaults) = defaults {
+                    // A value from the config satisfies the requirement
+                    arg = arg.required(false).default_values(defaults);
+                }
+                arg
+            });
+        }
+        Ok(cmd)
+    }
+
+    /// Adds the environment variables and the config values as defaults to the command line
+    /// options, so that the precedence is: command line > environment > profile > config > defaults.
+    ///
+    /// Every flag gets a `--no-<flag>` option, `negated` are the keys of the ones given on the
+    /// command line (see [`Config::negated`]), whose config value is ignored.
+    fn apply(&self, mut cmd: Command, negated: &[String]) -> Result<Command> {
+        cmd = Self::apply_args(cmd, None, &self.values, negated)
+            .with_context(ctx!("Invalid config file"))?;
+
+        let subcommands: Vec<_> = cmd.get_subcommands()
+            .map(|s| s.get_name().to_string())
+            .collect();
+
+        if let Some(name) = self.sections.keys().find(|name| !subcommands.contains(name)) {
+            anyhow::bail!("Invalid config file: unknown section `[{name}]`");
+        }
+
+        let empty = Table::new();
+        for name in subcommands {
+            let values = self.sections.get(&name).unwrap_or(&empty);
+            let mut err = None;
+            cmd = cmd.mut_subcommand(&name, |sub| {
+                match Self::apply_args(sub.clone(), Some(&name), values, negated) {
+                    Ok(sub) => sub,
+                    Err(e) => { err = Some(e); sub }
+                }
+            });
+            if let Some(e) = err {
+                return Err(e).with_context(ctx!("Invalid config file"));
+            }
+        }
+        Ok(cmd)
+    }
+
+    /// Parses `argv` with the options of `cmd` and this config, the command is returned with the
+    /// config values applied
+    pub fn get_matches<I, T>(&self, cmd: &Command, argv: I) -> Result<(Command, ArgMatches), clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let argv: Vec<_> = argv.into_iter().collect();
+        let invalid = |e: anyhow::Error| cmd.clone().error(ErrorKind::InvalidValue, format!("{e:#}"));
+
+        let mut applied = self.apply(cmd.clone(), &[]).map_err(invalid)?;
+        let mut matches = applied.clone().try_get_matches_from(argv.clone())?;
+        // Parse again without the config values of the flags turned off with `--no-<flag>`
+        let negated = Self::negated(&applied, &matches);
+        if !negated.is_empty() {
+            applied = self.apply(cmd.clone(), &negated).map_err(invalid)?;
+            matches = applied.clone().try_get_matches_from(argv)?;
+        }
+        Ok((applied, matches))
+    }
+
+    /// Checks if an option is set by the command line, the environment or this config, and isn't a
+    /// disabled flag
+    fn is_set(&self, section: Option<&str>, matches: &ArgMatches, id: &str) -> bool {
+        let from_config = match section {
+            Some(section) => self.sections.get(section).is_some_and(|s| s.contains_key(id)),
+            None => self.values.contains_key(id),
+        };
+        let set = match matches.value_source(id) {
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable) => true,
+            Some(ValueSource::DefaultValue) => from_config,
+            _ => false,
+        };
+        set && !matches!(matches.try_get_one::<bool>(id), Ok(Some(false)))
+    }
+
+    /// Keys of the flags turned off by a `--no-<flag>` option in the matches of a command built by
+    /// [`Config::apply`]
+    fn negated(cmd: &Command, matches: &ArgMatches) -> Vec<String> {
+        let negated_in = |section: Option<&str>, cmd: &Command, matches: &ArgMatches| -> Vec<String> {
+            cmd.get_arguments()
+                .filter(|a| is_configurable(a) && matches!(a.get_action(), ArgAction::SetTrue))
+                .filter(|a| {
+                    let (negation_id, _) = negation(a);
+                    matches.try_get_one::<bool>(&negation_id).ok().flatten() == Some(&true)
+                })
+                .map(|a| key(section, a.get_id().as_str()))
+                .collect()
+        };
+
+        let mut negated = negated_in(None, cmd, matches);
+        if let Some((name, sub_matches)) = matches.subcommand()
+            && let Some(sub) = cmd.find_subcommand(name)
+        {
+            negated.extend(negated_in(Some(name), sub, sub_matches));
+        }
+        negated
+    }
+
+    fn check_command_conflicts(&self, section: Option<&str>, cmd: &Command, matches: &ArgMatches) -> Result<()> {
+        for arg in cmd.get_arguments().filter(|a| !a.is_global_set()) {
+            let id = arg.get_id().as_str();
+            if !self.is_set(section, matches, id) {
+                continue;
+            }
+            for other in cmd.get_arg_conflicts_with(arg) {
+                let other_id = other.get_id().as_str();
+                if self.is_set(section, matches, other_id) {
+                    anyhow::bail!(
+                        "`{id}` ({}) cannot be used with `{other_id}` ({})",
+                        self.describe_source(matches, &key(section, id), id),
+                        self.describe_source(matches, &key(section, other_id), other_id),
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn describe_source(&self, matches: &ArgMatches, key: &str, id: &str) -> String {
+        match matches.value_source(id) {
+            Some(ValueSource::CommandLine) => "command line".to_string(),
+            Some(ValueSource::EnvVariable) => "environment".to_string(),
+            _ => self.describe(key),
+        }
+    }
+
+    /// Checks the conflicts between options that clap doesn't see, because config values are
+    /// defaults for it
+    pub fn check_conflicts(&self, cmd: &Command, matches: &ArgMatches) -> Result<()> {
+        self.check_command_conflicts(None, cmd, matches)?;
+        if let Some((name, sub_matches)) = matches.subcommand()
+            && let Some(sub) = cmd.find_subcommand(name)
+        {
+            self.check_command_conflicts(Some(name), sub, sub_matches)?;
+        }
+        Ok(())
+    }
+
+    /// Table of the config file that is not made of command line options (see [`DATA_SECTIONS`])
+    pub fn data_section(&self, name: &str) -> Option<&Table> {
+        self.data.get(name)
+    }
+
+    /// Describes where a value comes from, `key` is in the `section.name` format for tables
+    pub fn describe(&self, key: &str) -> String {
+        match self.sources.get(key) {
+            Some(Source::File) => "config file".to_string(),
+            Some(Source::Profile(name)) => format!("profile `{name}`"),
+            None => "default".to_string(),
+        }
+    }
+
+    fn format_values<'a>(arg: &Arg, values: impl Iterator<Item = &'a std::ffi::OsStr>) -> String {
+        let values: Vec<_> = values.map(|v| v.to_string_lossy().into_owned()).collect();
+        let format = |v: &String| match v.parse::<i64>() {
+            Ok(_) => v.clone(),
+            Err(_) if v == "true" || v == "false" => v.clone(),
+            Err(_) => format!("{v:?}"),
+        };
+        match values.as_slice() {
+            [value] if !matches!(arg.get_action(), clap::ArgAction::Append) => format(value),
+            values => format!("[{}]", values.iter().map(format).collect::<Vec<_>>().join(", ")),
+        }
+    }
+
+    fn show_arg(&self, out: &mut String, arg: &Arg, key: &str, matches: Option<&ArgMatches>) {
+        let id = arg.get_id().as_str();
+        let env = arg.get_env().map(|e| e.to_string_lossy().into_owned()).unwrap_or_default();
+
+        let (value, source) = match matches.and_then(|m| Some((m.value_source(id)?, m.get_raw(id)?))) {
+            Some((ValueSource::CommandLine, values)) => (Self::format_values(arg, values), "command line".to_string()),
+            Some((ValueSource::EnvVariable, values)) => (Self::format_values(arg, values), format!("env {env}")),
+            Some((_, values)) if matches.is_some_and(|m| {
+                m.try_get_one::<bool>(&negation(arg).0).ok().flatten() == Some(&true)
+            }) => {
+                (Self::format_values(arg, values), format!("command line --{}", negation(arg).1))
+            }
+            Some((_, values)) => (Self::format_values(arg, values), self.describe(key)),
+            // Not parsed (subcommand options): resolve the value by hand
+            None => match std::env::var_os(&env) {
+                Some(value) => (Self::format_values(arg, std::iter::once(value.as_os_str())), format!("env {env}")),
+                None if !arg.get_default_values().is_empty() => {
+                    (Self::format_values(arg, arg.get_default_values().iter().map(|v| v.as_os_str())), self.describe(key))
+                }
+                None => {
+                    let _ = writeln!(out, "# {id} = (unset)");
+                    return;
+                }
+            },
+        };
+        let line = format!("{id} = {value}");
+        let _ = writeln!(out, "{line:<48} # {source}");
+    }
+
+    /// Prints the effective value of every option and where it comes from
+    pub fn show(&self, cmd: &Command, matches: &ArgMatches) -> String {
+        let mut out = String::new();
+        match self.path {
+            Some(ref path) => { let _ = writeln!(out, "# config file: {path:?}"); }
+            None => { let _ = writeln!(out, "# config file: none"); }
+        }
+        if let Some(ref profile) = self.profile {
+            let _ = writeln!(out, "# profile: {profile}");
+        }
+        if let Err(e) = self.check_conflicts(cmd, matches) {
+            let _ = writeln!(out, "# conflict: {e:#}");
+        }
+        let _ = writeln!(out);
+
+        for arg in cmd.get_arguments().filter(|a| is_configurable(a)) {
+            self.show_arg(&mut out, arg, arg.get_id().as_str(), Some(matches));
+        }
+
+        for sub in cmd.get_subcommands() {
+            let args: Vec<_> = sub.get_arguments().filter(|a| is_configurable(a)).collect();
+            if args.is_empty() {
+                continue;
+            }
+            let name = sub.get_name();
+            let _ = writeln!(out, "\n[{name}]");
+            for arg in args {
+                self.show_arg(&mut out, arg, &format!("{name}.{}", arg.get_id()), None);
+            }
+        }
+
+        let mut data: Vec<_> = self.data.iter().collect();
+        data.sort_by_key(|(name, _)| name.as_str());
+        for (name, table) in data {
+            let _ = writeln!(out, "\n[{name}]");
+            for (key, value) in table {
+                let line = format!("{key} = {value}");
+                let _ = writeln!(out, "{line:<48} # {}", self.describe(&format!("{name}.{key}")));
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use clap::{Arg, ArgAction, ArgMatches, Command};
+
+    use super::Config;
+
+    /// Options like the ones of the real command line, each option set from the environment is
+    /// only used by one test, as the tests run in parallel
+    fn command() -> Command {
+        let flag = |id: &'static str, long: &'static str| Arg::new(id).long(long).action(ArgAction::SetTrue);
+        Command::new("test")
+            .arg(Arg::new("path").long("path"))
+            .arg(Arg::new("user_agent").long("user-agent"))
+            .arg(Arg::new("proxy").long("proxy"))
+            .arg(Arg::new("retries").long("retries"))
+            .arg(Arg::new("timeout").long("timeout").default_value("30"))
+            .arg(flag("overwrite", "overwrite"))
+            .arg(flag("no_check_missing_pages", "no-check-missing-pages").conflicts_with("overwrite"))
+            .arg(flag("fast", "fast"))
+            .subcommand(Command::new("query")
+                .arg(Arg::new("sort").long("sort").default_value("recent"))
+                .arg(flag("reverse", "reverse")))
+    }
+
+    fn parse(config: &str, profile: Option<&str>, argv: &[&str]) -> Result<ArgMatches> {
+        let config = Config::parse(config, None, profile)?;
+        let (cmd, matches) = config.get_matches(&command(), ["test"].iter().chain(argv))?;
+        config.check_conflicts(&cmd, &matches)?;
+        Ok(matches)
+    }
+
+    fn value<'a>(matches: &'a ArgMatches, id: &str) -> Option<&'a str> {
+        matches.get_one::<String>(id).map(String::as_str)
+    }
+
+    const PRECEDENCE: &str = r#"
+        path = "file"
+        user-agent = "file"
+        proxy = "file"
+        retries = 1
+
+        [query]
+        sort = "popular"
+
+        [profile.archive]
+        path = "profile"
+        user-agent = "profile"
+        retries = 2
+    "#;
+
+    #[test]
+    fn precedence() {
+        // SAFETY: no other test reads or writes this variable
+        unsafe { std::env::set_var("NHENTAI_RETRIES", "3") };
+
+        let matches = parse(PRECEDENCE, Some("archive"), &["--path", "cli"]).unwrap();
+        assert_eq!(value(&matches, "path"), Some("cli"));
+        assert_eq!(value(&matches, "retries"), Some("3"));
+        assert_eq!(value(&matches, "user_agent"), Some("profile"));
+        assert_eq!(value(&matches, "proxy"), Some("file"));
+        assert_eq!(value(&matches, "timeout"), Some("30"));
+
+        let matches = parse(PRECEDENCE, None, &["--retries", "4", "query"]).unwrap();
+        assert_eq!(value(&matches, "retries"), Some("4"));
+        assert_eq!(value(&matches, "user_agent"), Some("file"));
+        let (_, query) = matches.subcommand().unwrap();
+        assert_eq!(value(query, "sort"), Some("popular"));
+
+        let matches = parse(PRECEDENCE, None, &["query", "--sort", "popular-week"]).unwrap();
+        let (_, query) = matches.subcommand().unwrap();
+        assert_eq!(value(query, "sort"), Some("popular-week"));
+
+        assert!(parse(PRECEDENCE, Some("missing"), &[]).is_err());
+        assert!(parse("colour = true", None, &[]).is_err());
+        assert!(parse("[query]\ncolour = true", None, &[]).is_err());
+    }
+
+    #[test]
+    fn negation() {
+        let config = "overwrite = true\n[query]\nreverse = true";
+        let matches = parse(config, None, &["query"]).unwrap();
+        assert!(matches.get_flag("overwrite"));
+        assert!(matches.subcommand().unwrap().1.get_flag("reverse"));
+
+        let matches = parse(config, None, &["--no-overwrite", "query", "--no-reverse"]).unwrap();
+        assert!(!matches.get_flag("overwrite"));
+        assert!(!matches.subcommand().unwrap().1.get_flag("reverse"));
+
+        // The negation of a `no-` flag drops the prefix
+        let matches = parse("no-check-missing-pages = true", None, &["--check-missing-pages"]).unwrap();
+        assert!(!matches.get_flag("no_check_missing_pages"));
+
+        // The last one of a flag and its negation wins
+        let matches = parse("", None, &["--no-overwrite", "--overwrite"]).unwrap();
+        assert!(matches.get_flag("overwrite"));
+    }
+
+    #[test]
+    fn env_flags() {
+        // SAFETY: no other test reads or writes this variable
+        unsafe { std::env::set_var("NHENTAI_FAST", "false") };
+        let matches = parse("fast = true", None, &[]).unwrap();
+        assert!(!matches.get_flag("fast"));
+    }
+
+    #[test]
+    fn conflicts() {
+        let config = "overwrite = true\n[profile.archive]\nno-check-missing-pages = true";
+        let e = parse(config, Some("archive"), &[]).unwrap_err().to_string();
+        assert!(e.contains("profile `archive`") && e.contains("config file"), "{e}");
+        assert!(parse(config, None, &[]).is_ok());
+        assert!(parse(config, Some("archive"), &["--no-overwrite"]).is_ok());
+
+        let e = parse("no-check-missing-pages = true", None, &["--overwrite"]).unwrap_err().to_string();
+        assert!(e.contains("command line"), "{e}");
+        // Both on the command line is an error of clap
+        assert!(parse("", None, &["--overwrite", "--no-check-missing-pages"]).is_err());
+    }
+}
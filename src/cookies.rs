@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use cookie_store::CookieStore;
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::ctx;
+
+/// Domain used for cookies that don't specify one
+const DEFAULT_DOMAIN: &str = "nhentai.net";
+
+/// Cookie in the format exported by browser extensions (e.g. Cookie-Editor, EditThisCookie)
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonCookie {
+    name: String,
+    value: String,
+    domain: Option<String>,
+    path: Option<String>,
+    #[serde(default)]
+    secure: bool,
+    #[serde(default)]
+    host_only: bool,
+    expiration_date: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonCookies {
+    /// List of full cookies
+    List(Vec<JsonCookie>),
+    /// Plain `name: value` map, applied to nhentai.net
+    Map(HashMap<String, String>),
+}
+
+struct CookieSpec<'a> {
+    name: &'a str,
+    value: &'a str,
+    domain: &'a str,
+    include_subdomains: bool,
+    path: &'a str,
+    secure: bool,
+    /// Unix timestamp, `None` for session cookies
+    expires: Option<u64>,
+}
+
+impl CookieSpec<'_> {
+    /// Adds the cookie to the store, returns false if the cookie is already expired
+    fn insert(&self, store: &mut CookieStore) -> Result<bool> {
+        let host = self.domain.trim_start_matches('.');
+        let path = if self.path.starts_with('/') { self.path } else { "/" };
+        let url = Url::parse(&format!("https://{host}{path}"))
+            .with_context(ctx!("Invalid domain `{}` for cookie `{}`", self.domain, self.name))?;
+
+        let mut cookie = format!("{}={}; Path={path}", self.name, self.value);
+        if self.include_subdomains {
+            cookie.push_str(&format!("; Domain={host}"));
+        }
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        if let Some(expires) = self.expires {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+            let Some(max_age) = expires.checked_sub(now).filter(|age| *age > 0) else {
+                return Ok(false);
+            };
+            cookie.push_str(&format!("; Max-Age={max_age}"));
+        }
+
+        store.parse(&cookie, &url)
+            .with_context(ctx!("Cannot add cookie `{}` for domain `{}`", self.name, self.domain))?;
+        Ok(true)
+    }
+}
+
+fn import_netscape(store: &mut CookieStore, text: &str) -> Result<usize> {
+    let mut count = 0;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        // Curl marks HttpOnly cookies with this prefix, everything else starting with # is a comment
+        let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<_> = line.split('\t').collect();
+        let &[domain, include_subdomains, path, secure, expires, name, value] = fields.as_slice() else {
+            anyhow::bail!("Invalid cookies.txt line {}, expected 7 tab separated fields", i + 1);
+        };
+        let expires: u64 = expires.parse()
+            .with_context(ctx!("Invalid expiration on cookies.txt line {}", i + 1))?;
+
+        let spec = CookieSpec {
+            name,
+            value,
+            domain,
+            include_subdomains: include_subdomains.eq_ignore_ascii_case("TRUE"),
+            path,
+            secure: secure.eq_ignore_ascii_case("TRUE"),
+            expires: (expires != 0).then_some(expires),
+        };
+        if spec.insert(store)? {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+fn import_json(store: &mut CookieStore, text: &str) -> Result<usize> {
+    let cookies: JsonCookies = serde_json::from_str(text)
+        .with_context(ctx!("Invalid JSON cookie file"))?;
+
+    let mut count = 0;
+    match cookies {
+        JsonCookies::List(cookies) => for c in &cookies {
+            let domain = c.domain.as_deref().unwrap_or(DEFAULT_DOMAIN);
+            let spec = CookieSpec {
+                name: &c.name,
+                value: &c.value,
+                domain,
+                include_subdomains: !c.host_only && (c.domain.is_none() || domain.starts_with('.')),
+                path: c.path.as_deref().unwrap_or("/"),
+                secure: c.secure,
+                expires: c.expiration_date.map(|e| e as u64),
+            };
+            if spec.insert(store)? {
+                count += 1;
+            }
+        }
+        JsonCookies::Map(cookies) => for (name, value) in &cookies {
+            let spec = CookieSpec {
+                name,
+                value,
+                domain: DEFAULT_DOMAIN,
+                include_subdomains: true,
+                path: "/",
+                secure: false,
+                expires: None,
+            };
+            if spec.insert(store)? {
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+/// Adds the cookies from a Netscape cookies.txt file or a JSON file to the store.
+///
+/// Returns the number of cookies imported, already expired cookies are skipped.
+pub fn import(store: &mut CookieStore, path: &Path) -> Result<usize> {
+    let text = std::fs::read_to_string(path)
+        .with_context(ctx!("Cannot read cookie file {path:?}"))?;
+
+    let trimmed = text.trim_start();
+    if trimmed.starts_with('[') || trimmed.starts_with('{') {
+        import_json(store, &text)
+    } else {
+        import_netscape(store, &text)
+    }
+    .with_context(ctx!("Cannot import cookies from {path:?}"))
+}
+
+/// Loads a cookie jar saved by [`save_jar`], a missing file is an empty jar
+pub fn load_jar(path: &Path) -> Result<CookieStore> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(CookieStore::default()),
+        Err(e) => return Err(e).with_context(ctx!("Cannot open cookie jar {path:?}")),
+    };
+    cookie_store::serde::json::load(BufReader::new(file))
+        .map_err(|e| anyhow::anyhow!("{e}"))
+        .with_context(ctx!("Cannot read cookie jar {path:?}"))
+}
+
+/// Saves the persistent cookies of the store to a file
+pub fn save_jar(store: &CookieStore, path: &Path) -> Result<()> {
+    let file = File::create(path)
+        .with_context(ctx!("Cannot create cookie jar {path:?}"))?;
+    cookie_store::serde::json::save(store, &mut BufWriter::new(file))
+        .map_err(|e| anyhow::anyhow!("{e}"))
+        .with_context(ctx!("Cannot write cookie jar {path:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use cookie_store::CookieStore;
+    use reqwest::Url;
+
+    use super::{import_json, import_netscape};
+
+    const NETSCAPE: &str = include_str!("../tests/fixtures/cookies/cookies.txt");
+    const JSON: &str = include_str!("../tests/fixtures/cookies/cookies.json");
+
+    /// Names of the cookies sent to `url`, sorted
+    fn sent_to(store: &CookieStore, url: &str) -> Vec<String> {
+        let url = Url::parse(url).unwrap();
+        let mut names: Vec<_> = store.matches(&url).iter().map(|c| c.name().to_string()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn netscape() {
+        let mut store = CookieStore::default();
+        // The expired cf_clearance is skipped
+        assert_eq!(import_netscape(&mut store, NETSCAPE).unwrap(), 2);
+        assert_eq!(store.get("nhentai.net", "/", "sessionid").unwrap().value(), "s3ss10n");
+        // The trailing \r is not part of the value
+        assert_eq!(store.get("nhentai.net", "/", "csrftoken").unwrap().value(), "Xy0dcWzJ8mE0aQ7bLkMf");
+
+        assert_eq!(sent_to(&store, "https://nhentai.net/"), ["csrftoken", "sessionid"]);
+        // Only the cookie with the subdomain flag is sent to the other hosts
+        assert_eq!(sent_to(&store, "https://i3.nhentai.net/"), ["sessionid"]);
+        // Secure cookies are not sent over http
+        assert_eq!(sent_to(&store, "http://nhentai.net/"), ["csrftoken"]);
+    }
+
+    #[test]
+    fn netscape_malformed() {
+        let mut store = CookieStore::default();
+        let e = import_netscape(&mut store, "# comment\nnhentai.net\tFALSE\t/\n").unwrap_err();
+        assert!(format!("{e:#}").contains("line 2"), "{e:#}");
+
+        let e = import_netscape(&mut store, "nhentai.net\tFALSE\t/\tFALSE\tnever\ta\tb\n").unwrap_err();
+        assert!(format!("{e:#}").contains("Invalid expiration on cookies.txt line 1"), "{e:#}");
+    }
+
+    #[test]
+    fn json_list() {
+        let mut store = CookieStore::default();
+        assert_eq!(import_json(&mut store, JSON).unwrap(), 2);
+        assert!(store.get("nhentai.net", "/", "cf_clearance").is_none());
+
+        assert_eq!(sent_to(&store, "https://nhentai.net/"), ["csrftoken", "sessionid"]);
+        // hostOnly cookies stay on nhentai.net
+        assert_eq!(sent_to(&store, "https://t5.nhentai.net/"), ["sessionid"]);
+    }
+
+    #[test]
+    fn json_map() {
+        let mut store = CookieStore::default();
+        let count = import_json(&mut store, r#"{ "sessionid": "s3ss10n", "csrftoken": "tok" }"#).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(sent_to(&store, "https://i3.nhentai.net/"), ["csrftoken", "sessionid"]);
+    }
+
+    #[test]
+    fn json_malformed() {
+        let mut store = CookieStore::default();
+        assert!(import_json(&mut store, r#"[{ "name": "sessionid" }]"#).is_err());
+        assert!(import_json(&mut store, "[{").is_err());
+        let e = import_json(&mut store, r#"[{ "name": "a", "value": "b", "domain": "bad domain" }]"#).unwrap_err();
+        assert!(format!("{e:#}").contains("Invalid domain `bad domain`"), "{e:#}");
+    }
+}
@@ -0,0 +1,237 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde_json::json;
+
+use crate::gallery::Gallery;
+use crate::{App, BatchCli, ctx, http};
+
+/// Formats of the gallery lists
+#[derive(clap::ValueEnum, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum ListFormat {
+    /// One id per line, can be read again by the `batch` mode
+    #[default]
+    Ids,
+    /// One JSON object per line
+    Jsonl,
+    /// Comma separated values, with a header line
+    Csv,
+}
+
+/// Writes one record per gallery of a list to stdout or a file
+pub struct ListWriter {
+    format: ListFormat,
+    /// Whether the records include the gallery info, or only the id
+    enriched: bool,
+    out: Box<dyn Write>,
+}
+
+fn csv_field(text: &str) -> String {
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+impl ListWriter {
+    /// Creates the writer, `path` is the output file (stdout if `None` or `-`)
+    pub fn new(format: ListFormat, path: Option<&Path>, enriched: bool) -> Result<Self> {
+        let out: Box<dyn Write> = match path {
+            Some(path) if path != Path::new("-") => {
+                let file = File::create(path)
+                    .with_context(ctx!("Cannot create list file {path:?}"))?;
+                Box::new(BufWriter::new(file))
+            }
+            _ => Box::new(io::stdout()),
+        };
+
+        let mut writer = Self { format, enriched, out };
+        if format == ListFormat::Csv {
+            let header = match enriched {
+                true => "id,media_id,title,japanese_title,pages,favorites,uploaded,tags",
+                false => "id",
+            };
+            writer.line(header.to_string())?;
+        }
+        Ok(writer)
+    }
+
+    pub fn enriched(&self) -> bool {
+        self.enriched
+    }
+
+    fn line(&mut self, line: String) -> Result<()> {
+        writeln!(self.out, "{line}")
+            .with_context(ctx!("Cannot write gallery list"))
+    }
+
+    /// Writes the record of a gallery, `gallery` must be given if the list is enriched
+    pub fn write(&mut self, id: u32, gallery: Option<&Gallery>) -> Result<()> {
+        let gallery = gallery.filter(|_| self.enriched);
+        let line = match (self.format, gallery) {
+            (ListFormat::Ids, _) => id.to_string(),
+            (ListFormat::Jsonl, None) => json!({ "id": id }).to_string(),
+            (ListFormat::Jsonl, Some(g)) => json!({
+                "id": id,
+                "media_id": g.media_id,
+                "title": g.title,
+                "pages": g.pages(),
+                "favorites": g.num_favorites,
+                "uploaded": g.upload_date,
+                "tags": g.tags.iter()
+                    .map(|t| json!({ "type": t.kind, "name": t.name }))
+                    .collect::<Vec<_>>(),
+            }).to_string(),
+            (ListFormat::Csv, None) => id.to_string(),
+            (ListFormat::Csv, Some(g)) => {
+                let tags: Vec<_> = g.tags.iter().map(|t| format!("{}:{}", t.kind, t.name)).collect();
+                [
+                    id.to_string(),
+                    g.media_id.clone(),
+                    g.title.pretty.clone(),
+                    g.title.japanese.clone(),
+                    g.pages().to_string(),
+                    g.num_favorites.to_string(),
+                    g.upload_date.to_string(),
+                    tags.join(";"),
+                ]
+                .iter()
+                .map(|f| csv_field(f))
+                .collect::<Vec<_>>()
+                .join(",")
+            }
+        };
+        self.line(line)
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.out.flush()
+            .with_context(ctx!("Cannot write gallery list"))
+    }
+}
+
+fn read_json_id(line: &str) -> Result<u32> {
+    let record: serde_json::Value = serde_json::from_str(line)?;
+    let id = record.get("id")
+        .and_then(serde_json::Value::as_u64)
+        .context("Missing `id` field")?;
+    id.try_into().context("Gallery id is too large")
+}
+
+/// Reads the ids of an id list (e.g. written by `--list`): one gallery per line as an id, an url or
+/// a jsonl record, blank lines, lines starting with `#` and the header of csv lists are ignored
+pub fn read_ids(text: &str) -> Result<Vec<u32>> {
+    text.lines()
+        .enumerate()
+        .map(|(n, line)| (n + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .filter(|(n, line)| *n != 1 || !line.starts_with("id"))
+        .map(|(n, line)| {
+            if line.starts_with('{') {
+                return read_json_id(line)
+                    .with_context(ctx!("Invalid gallery record at line {n}: `{line}`"));
+            }
+            // Also accept the first column of csv lists
+            let first = line.split([',', '\t', ' ']).next().unwrap_or(line);
+            let id = match reqwest::Url::parse(first) {
+                Ok(url) => crate::query::QueryInfo::parse_gallery_path(url.path()),
+                Err(_) => first.parse().map_err(Into::into),
+            };
+            id.with_context(ctx!("Invalid gallery at line {n}: `{line}`"))
+        })
+        .collect()
+}
+
+impl App {
+    /// Writes the records of `ids` to the list, loading the galleries if the list is enriched
+    pub async fn list_galleries(&self, writer: &mut ListWriter, ids: &[u32]) -> Result<()> {
+        for &id in ids {
+            if !writer.enriched() {
+                writer.write(id, None)?;
+                continue;
+            }
+            match self.load_gallery(id).await {
+                Ok(gallery) if self.is_excluded(&gallery, &[]) => {}
+                Ok(gallery) => writer.write(id, Some(&gallery))?,
+                Err(e) if http::is_blocked(&e) => return Err(e),
+                Err(e) => {
+                    log::warn!("Failed to load gallery: {id}\nError: {e:?}");
+                    self.summary.failed();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn download_batch(&self, batch: &BatchCli) -> Result<()> {
+        let text = match batch.file.as_os_str() == "-" {
+            true => std::io::read_to_string(std::io::stdin())
+                .with_context(ctx!("Cannot read id list from stdin"))?,
+            false => std::fs::read_to_string(&batch.file)
+                .with_context(ctx!("Cannot read id list {:?}", batch.file))?,
+        };
+        let mut ids = read_ids(&text)?;
+        let mut seen = std::collections::HashSet::new();
+        ids.retain(|id| seen.insert(*id));
+        log::info!("Found {} galleries in the id list", ids.len());
+
+        if batch.list.list {
+            let mut writer = batch.list.writer()?;
+            self.list_galleries(&mut writer, &ids).await?;
+            return writer.finish();
+        }
+
+        let count = ids.len();
+        for (i, &id) in ids.iter().enumerate() {
+            match self.download_gallery(id, Some((i + 1, count))).await {
+                Err(e) if http::is_blocked(&e) => return Err(e),
+                Err(e) => log::warn!("Failed to download gallery: {id}\nError: {e:?}"),
+                Ok(()) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::read_ids;
+
+    #[test]
+    fn ids_and_urls() {
+        let text = "# saved list\n177013\n\nhttps://nhentai.net/g/228922/\n  421337  \n";
+        assert_eq!(read_ids(text).unwrap(), [177013, 228922, 421337]);
+    }
+
+    #[test]
+    fn csv() {
+        let text = "id,media_id,title,japanese_title,pages,favorites,uploaded,tags\n\
+            177013,987654,\"A, title\",,225,50000,1476793729,tag:full color\n\
+            228922,1212121,Other,,30,100,1520000000,\n";
+        assert_eq!(read_ids(text).unwrap(), [177013, 228922]);
+    }
+
+    #[test]
+    fn jsonl() {
+        let text = concat!(
+            r#"{"id":177013}"#, "\n",
+            r#"{"id":228922,"media_id":"1212121","title":{"english":"x","japanese":"","pretty":"x"},"pages":30}"#, "\n",
+        );
+        assert_eq!(read_ids(text).unwrap(), [177013, 228922]);
+
+        let e = read_ids("177013\n{\"media_id\":\"1\"}\n").unwrap_err();
+        assert!(format!("{e:#}").contains("line 2"), "{e:#}");
+        assert!(read_ids("{\"id\":\"abc\"}").is_err());
+        assert!(read_ids("{\"id\":1").is_err());
+    }
+
+    #[test]
+    fn invalid_line() {
+        let e = read_ids("177013\nabc\n").unwrap_err();
+        assert!(format!("{e:#}").contains("Invalid gallery at line 2: `abc`"), "{e:#}");
+        assert!(read_ids("https://nhentai.net/tag/x/").is_err());
+    }
+}
@@ -0,0 +1,585 @@
+use std::fmt;
+use std::iter::Peekable;
+use std::str::{Chars, FromStr};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use regex::Regex;
+
+use crate::ctx;
+use crate::gallery::Gallery;
+
+// Filter expressions
+//
+// expr       := or
+// or         := and (("or" | "||") and)*
+// and        := unary (("and" | "&&")? unary)*
+// unary      := ("not" | "!" | "-") unary | primary
+// primary    := "(" expr ")" | field op value | kind ":" value
+//
+// Fields: favorites, pages, id, uploaded (unix timestamp), age (e.g. 30d), title
+// Kinds: tag, artist, group, parody, character, language, category, title (substring)
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    Not,
+    And,
+    Or,
+    Colon,
+    Op(CmpOp),
+    Word(String),
+    Quoted(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CmpOp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    Match,
+}
+
+impl CmpOp {
+    fn compare(self, lhs: u64, rhs: u64) -> bool {
+        match self {
+            Self::Lt => lhs < rhs,
+            Self::Le => lhs <= rhs,
+            Self::Gt => lhs > rhs,
+            Self::Ge => lhs >= rhs,
+            Self::Eq | Self::Match => lhs == rhs,
+            Self::Ne => lhs != rhs,
+        }
+    }
+}
+
+impl fmt::Display for CmpOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Lt => "<",
+            Self::Le => "<=",
+            Self::Gt => ">",
+            Self::Ge => ">=",
+            Self::Eq => "=",
+            Self::Ne => "!=",
+            Self::Match => "~",
+        })
+    }
+}
+
+fn is_word_char(ch: char) -> bool {
+    !ch.is_whitespace() && !matches!(ch, '(' | ')' | '!' | '<' | '>' | '=' | '~' | ':' | '"' | '&' | '|')
+}
+
+fn read_quoted(chars: &mut Peekable<Chars>) -> Result<String> {
+    let mut out = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(out),
+            Some('\\') => out.push(chars.next().context("Unterminated string in filter")?),
+            Some(ch) => out.push(ch),
+            None => anyhow::bail!("Unterminated string in filter"),
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&ch) = chars.peek() {
+        if ch.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        let starts_term = !matches!(tokens.last(), Some(Token::Op(_) | Token::Colon));
+        let token = match ch {
+            '(' => { chars.next(); Token::LParen }
+            ')' => { chars.next(); Token::RParen }
+            ':' => { chars.next(); Token::Colon }
+            '"' => { chars.next(); Token::Quoted(read_quoted(&mut chars)?) }
+            '~' => { chars.next(); Token::Op(CmpOp::Match) }
+            '&' | '|' => {
+                chars.next();
+                if chars.next() != Some(ch) {
+                    anyhow::bail!("Unexpected `{ch}` in filter, use `{ch}{ch}`");
+                }
+                if ch == '&' { Token::And } else { Token::Or }
+            }
+            '!' | '<' | '>' | '=' => {
+                chars.next();
+                let eq = chars.next_if_eq(&'=').is_some();
+                match (ch, eq) {
+                    ('!', false) => Token::Not,
+                    ('!', true) => Token::Op(CmpOp::Ne),
+                    ('<', false) => Token::Op(CmpOp::Lt),
+                    ('<', true) => Token::Op(CmpOp::Le),
+                    ('>', false) => Token::Op(CmpOp::Gt),
+                    ('>', true) => Token::Op(CmpOp::Ge),
+                    ('=', _) => Token::Op(CmpOp::Eq),
+                    _ => unreachable!(),
+                }
+            }
+            // A dash before a term negates it, like in the site search
+            '-' if starts_term => { chars.next(); Token::Not }
+            _ => {
+                let mut word = String::new();
+                while let Some(ch) = chars.next_if(|c| is_word_char(*c)) {
+                    word.push(ch);
+                }
+                match word.to_lowercase().as_str() {
+                    "and" if starts_term => Token::And,
+                    "or" if starts_term => Token::Or,
+                    "not" if starts_term => Token::Not,
+                    _ => Token::Word(word),
+                }
+            }
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum NumField {
+    Favorites,
+    Pages,
+    Id,
+    Uploaded,
+    Age,
+}
+
+impl NumField {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "favorites" | "num_favorites" => Some(Self::Favorites),
+            "pages" => Some(Self::Pages),
+            "id" => Some(Self::Id),
+            "uploaded" | "upload_date" => Some(Self::Uploaded),
+            "age" => Some(Self::Age),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Favorites => "favorites",
+            Self::Pages => "pages",
+            Self::Id => "id",
+            Self::Uploaded => "uploaded",
+            Self::Age => "age",
+        }
+    }
+
+    fn value(self, gallery: &Gallery) -> u64 {
+        match self {
+            Self::Favorites => gallery.num_favorites as u64,
+            Self::Pages => gallery.pages() as u64,
+            Self::Id => gallery.id as u64,
+            Self::Uploaded => gallery.upload_date,
+            Self::Age => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+                now.saturating_sub(gallery.upload_date)
+            }
+        }
+    }
+}
+
+/// Parses a duration with an unit (h, d, w, m, y) into seconds
+pub fn parse_duration(text: &str) -> Result<u64> {
+    let split = text.find(|c: char| !c.is_ascii_digit()).unwrap_or(text.len());
+    let (num, unit) = text.split_at(split);
+    let num: u64 = num.parse()
+        .with_context(ctx!("Invalid duration `{text}`"))?;
+    let unit = match unit {
+        "" | "s" => 1,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        "m" => 30 * 24 * 60 * 60,
+        "y" => 365 * 24 * 60 * 60,
+        _ => anyhow::bail!("Invalid duration unit in `{text}`, use h, d, w, m or y"),
+    };
+    num.checked_mul(unit)
+        .with_context(ctx!("Duration `{text}` is too long"))
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Not(Box<Expr>),
+    /// Gallery has a tag of this kind with this name, `*` matches any name
+    Tag { kind: String, name: String },
+    /// Any of the titles contains the text (case insensitive)
+    TitleContains(String),
+    TitleRegex(Regex),
+    TitleEq(String),
+    Compare { field: NumField, op: CmpOp, value: u64, text: String },
+}
+
+const TAG_KINDS: &[&str] = &["tag", "artist", "group", "parody", "character", "language", "category"];
+
+fn normalize_tag(name: &str) -> String {
+    name.trim().to_lowercase().replace('-', " ")
+}
+
+/// Quotes a string so that [`read_quoted`] reads it back
+fn quote_always(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn quote(text: &str) -> String {
+    if !text.is_empty() && text.chars().all(is_word_char) {
+        text.to_string()
+    } else {
+        quote_always(text)
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |f: &mut fmt::Formatter<'_>, exprs: &[Expr], sep: &str| {
+            for (i, e) in exprs.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(sep)?;
+                }
+                match e {
+                    Self::And(_) | Self::Or(_) => write!(f, "({e})")?,
+                    e => write!(f, "{e}")?,
+                }
+            }
+            Ok(())
+        };
+        match self {
+            Self::And(exprs) => join(f, exprs, " and "),
+            Self::Or(exprs) => join(f, exprs, " or "),
+            Self::Not(e) => match **e {
+                Self::And(_) | Self::Or(_) => write!(f, "not ({e})"),
+                ref e => write!(f, "not {e}"),
+            },
+            Self::Tag { kind, name } => write!(f, "{kind}:{}", quote(name)),
+            Self::TitleContains(text) => write!(f, "title:{}", quote(text)),
+            Self::TitleRegex(re) => write!(f, "title ~ {}", quote_always(re.as_str())),
+            Self::TitleEq(text) => write!(f, "title = {}", quote(text)),
+            Self::Compare { field, op, text, .. } => write!(f, "{} {op} {text}", field.name()),
+        }
+    }
+}
+
+impl Expr {
+    fn matches(&self, gallery: &Gallery) -> bool {
+        let titles = || [&gallery.title.english, &gallery.title.japanese, &gallery.title.pretty];
+        match self {
+            Self::And(exprs) => exprs.iter().all(|e| e.matches(gallery)),
+            Self::Or(exprs) => exprs.iter().any(|e| e.matches(gallery)),
+            Self::Not(e) => !e.matches(gallery),
+            Self::Tag { kind, name } => gallery.tags.iter()
+                .filter(|t| t.kind == *kind)
+                .any(|t| name == "*" || normalize_tag(&t.name) == *name),
+            Self::TitleContains(text) => titles().iter().any(|t| t.to_lowercase().contains(text)),
+            Self::TitleRegex(re) => titles().iter().any(|t| re.is_match(t)),
+            Self::TitleEq(text) => titles().iter().any(|t| t.to_lowercase() == *text),
+            Self::Compare { field, op, value, .. } => op.compare(field.value(gallery), *value),
+        }
+    }
+
+    /// Finds the part of the expression that rejects the gallery
+    fn reason(&self, gallery: &Gallery) -> Option<String> {
+        if self.matches(gallery) {
+            return None;
+        }
+        match self {
+            Self::And(exprs) => exprs.iter().find_map(|e| e.reason(gallery)),
+            e => Some(e.to_string()),
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut exprs = vec![self.parse_and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            exprs.push(self.parse_and()?);
+        }
+        Ok(if exprs.len() == 1 { exprs.remove(0) } else { Expr::Or(exprs) })
+    }
+
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut exprs = vec![self.parse_unary()?];
+        loop {
+            match self.peek() {
+                None | Some(Token::Or | Token::RParen) => break,
+                Some(Token::And) => { self.next(); }
+                // Terms next to each other are joined with and, like in the site search
+                Some(_) => {}
+            }
+            exprs.push(self.parse_unary()?);
+        }
+        Ok(if exprs.len() == 1 { exprs.remove(0) } else { Expr::And(exprs) })
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr> {
+        if self.peek() == Some(&Token::Not) {
+            self.next();
+            return Ok(Expr::Not(Box::new(self.parse_unary()?)));
+        }
+        self.parse_primary()
+    }
+
+    fn parse_value(&mut self) -> Result<String> {
+        match self.next() {
+            Some(Token::Word(w) | Token::Quoted(w)) => Ok(w),
+            Some(t) => anyhow::bail!("Expected a value in filter, found {t:?}"),
+            None => anyhow::bail!("Expected a value at the end of the filter"),
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr> {
+        let name = match self.next() {
+            Some(Token::LParen) => {
+                let expr = self.parse_or()?;
+                if self.next() != Some(Token::RParen) {
+                    anyhow::bail!("Missing `)` in filter");
+                }
+                return Ok(expr);
+            }
+            Some(Token::Word(name)) => name.to_lowercase(),
+            Some(t) => anyhow::bail!("Unexpected {t:?} in filter"),
+            None => anyhow::bail!("Unexpected end of filter"),
+        };
+
+        match self.next() {
+            Some(Token::Colon) => {
+                let value = self.parse_value()?;
+                let kind = match name.as_str() {
+                    "title" => return Ok(Expr::TitleContains(value.to_lowercase())),
+                    // Plurals are accepted like in the site search
+                    "tags" => "tag",
+                    "artists" => "artist",
+                    "groups" => "group",
+                    "parodies" => "parody",
+                    "characters" => "character",
+                    "languages" => "language",
+                    "categories" => "category",
+                    kind if TAG_KINDS.contains(&kind) => kind,
+                    kind => anyhow::bail!("Unknown tag type `{kind}` in filter, use one of {TAG_KINDS:?} or title"),
+                };
+                Ok(Expr::Tag { kind: kind.to_string(), name: normalize_tag(&value) })
+            }
+            Some(Token::Op(op)) => {
+                let value = self.parse_value()?;
+                if name == "title" {
+                    return match op {
+                        CmpOp::Match => Regex::new(&value)
+                            .with_context(ctx!("Invalid title regex `{value}`"))
+                            .map(Expr::TitleRegex),
+                        CmpOp::Eq => Ok(Expr::TitleEq(value.to_lowercase())),
+                        CmpOp::Ne => Ok(Expr::Not(Box::new(Expr::TitleEq(value.to_lowercase())))),
+                        op => anyhow::bail!("Operator `{op}` can't be used with title, use `~`, `=` or `!=`"),
+                    };
+                }
+
+                let field = NumField::from_name(&name)
+                    .with_context(ctx!("Unknown field `{name}` in filter, use favorites, pages, id, uploaded, age or title"))?;
+                if op == CmpOp::Match {
+                    anyhow::bail!("Operator `~` can only be used with title");
+                }
+                let parsed = match field {
+                    NumField::Age => parse_duration(&value)?,
+                    _ => value.parse()
+                        .with_context(ctx!("Invalid number `{value}` for {name}"))?,
+                };
+                Ok(Expr::Compare { field, op, value: parsed, text: value })
+            }
+            _ => anyhow::bail!("Expected `:` or a comparison after `{name}` in filter"),
+        }
+    }
+}
+
+/// Filter on the gallery metadata, checked before downloading
+#[derive(Debug, Clone)]
+pub struct Filter {
+    expr: Expr,
+}
+
+impl FromStr for Filter {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let tokens = tokenize(text)?;
+        if tokens.is_empty() {
+            anyhow::bail!("Empty filter");
+        }
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.parse_or()?;
+        if let Some(t) = parser.peek() {
+            anyhow::bail!("Unexpected {t:?} in filter");
+        }
+        Ok(Self { expr })
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.expr.fmt(f)
+    }
+}
+
+impl Filter {
+    /// Checks the gallery, returning the part of the filter that rejects it
+    pub fn check(&self, gallery: &Gallery) -> Result<(), String> {
+        match self.expr.reason(gallery) {
+            Some(reason) => Err(reason),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Filter, parse_duration};
+    use crate::gallery::Gallery;
+
+    // Gallery 398120: "Smile 😀 Again", 3 pages, 87 favorites, uploaded 1651234567,
+    // language:japanese, category:manga, tag:sole female
+    const EMOJI: &str = include_str!("../tests/fixtures/gallery/emoji.html");
+
+    fn parse(text: &str) -> Filter {
+        text.parse().unwrap_or_else(|e| panic!("Cannot parse `{text}`: {e:#}"))
+    }
+
+    /// Checks that `text` is displayed as `expected`, and that it's read back the same
+    fn roundtrip(text: &str, expected: &str) {
+        assert_eq!(parse(text).to_string(), expected, "displaying `{text}`");
+        assert_eq!(parse(expected).to_string(), expected, "reading back `{expected}`");
+    }
+
+    fn matches(text: &str) -> bool {
+        let gallery = Gallery::from_page(EMOJI).unwrap();
+        parse(text).check(&gallery).is_ok()
+    }
+
+    fn error(text: &str) -> String {
+        match text.parse::<Filter>() {
+            Ok(f) => panic!("`{text}` was parsed as `{f}`"),
+            Err(e) => format!("{e:#}"),
+        }
+    }
+
+    #[test]
+    fn display_roundtrip() {
+        roundtrip(r#"tag:"full color" artist:someone"#, r#"tag:"full color" and artist:someone"#);
+        roundtrip("tags:full-color", r#"tag:"full color""#);
+        roundtrip("-tag:yaoi", "not tag:yaoi");
+        roundtrip("! tag:yaoi", "not tag:yaoi");
+        roundtrip("pages>=20 && favorites<1000 || id=5", "(pages >= 20 and favorites < 1000) or id = 5");
+        roundtrip("(tag:a or tag:b) and not (tag:c || tag:d)", "(tag:a or tag:b) and not (tag:c or tag:d)");
+        roundtrip("age <= 30d", "age <= 30d");
+        roundtrip("title != Smile", "not title = smile");
+        roundtrip("title:AND", "title:and");
+        roundtrip(r#"title:"say \"hi\" \\ bye""#, r#"title:"say \"hi\" \\ bye""#);
+        roundtrip("title:\"tab\tand\nnewline\"", "title:\"tab\tand\nnewline\"");
+        roundtrip(r#"title ~ "^\\[.*\\] \"x\"$""#, r#"title ~ "^\\[.*\\] \"x\"$""#);
+    }
+
+    #[test]
+    fn precedence() {
+        // and binds tighter than or
+        roundtrip("tag:a or tag:b tag:c", "tag:a or (tag:b and tag:c)");
+        roundtrip("tag:a and tag:b or tag:c", "(tag:a and tag:b) or tag:c");
+        assert!(matches("language:japanese or tag:x category:doujinshi"));
+        // not binds tighter than and and or
+        assert!(matches("-language:japanese or category:manga"));
+        assert!(!matches("not language:japanese and category:manga"));
+        assert!(matches("not (language:japanese and category:doujinshi)"));
+        assert!(matches("not not language:japanese"));
+        // A dash inside a word or after a colon is not a negation
+        assert!(matches("tag:sole-female"));
+        roundtrip("title:-x", "title:-x");
+    }
+
+    #[test]
+    fn gallery_matches() {
+        assert!(matches("language:japanese"));
+        assert!(matches("languages:Japanese"));
+        assert!(matches("tag:\"sole female\""));
+        assert!(matches("language:*"));
+        assert!(!matches("parody:*"));
+        assert!(!matches("language:english"));
+
+        assert!(matches("pages = 3"));
+        assert!(matches("favorites > 86"));
+        assert!(!matches("favorites >= 88"));
+        assert!(!matches("id != 398120"));
+        assert!(matches("uploaded < 1651234568"));
+        assert!(matches("age > 1d"));
+
+        assert!(matches("title:smile"));
+        assert!(matches("title = \"SMILE \u{1F600} again\""));
+        assert!(matches("title ~ ^Smile"));
+        // Regexes are case sensitive
+        assert!(!matches("title ~ ^smile"));
+        assert!(matches("title ~ \"(?i)^smile\""));
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("12").unwrap(), 12);
+        assert_eq!(parse_duration("12s").unwrap(), 12);
+        assert_eq!(parse_duration("2h").unwrap(), 2 * 60 * 60);
+        assert_eq!(parse_duration("30d").unwrap(), 30 * 24 * 60 * 60);
+        assert_eq!(parse_duration("2w").unwrap(), 14 * 24 * 60 * 60);
+        assert_eq!(parse_duration("1m").unwrap(), 30 * 24 * 60 * 60);
+        assert_eq!(parse_duration("1y").unwrap(), 365 * 24 * 60 * 60);
+
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("d").is_err());
+        assert!(parse_duration("-1d").is_err());
+        assert!(parse_duration("5x").unwrap_err().to_string().contains("Invalid duration unit"));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(error("").contains("Empty filter"));
+        assert!(error("   ").contains("Empty filter"));
+        assert!(error("tag:").contains("Expected a value at the end of the filter"));
+        assert!(error("tag:a)").contains("Unexpected RParen"));
+        assert!(error("(tag:a").contains("Missing `)`"));
+        assert!(error("title:\"abc").contains("Unterminated string"));
+        assert!(error("title:\"abc\\").contains("Unterminated string"));
+        assert!(error("tag:a & tag:b").contains("use `&&`"));
+        assert!(error("tag:a | tag:b").contains("use `||`"));
+        assert!(error("color:red").contains("Unknown tag type `color`"));
+        assert!(error("color > 3").contains("Unknown field `color`"));
+        assert!(error("pages > many").contains("Invalid number `many` for pages"));
+        assert!(error("age > 3x").contains("Invalid duration unit"));
+        assert!(error("age > 99999999999999y").contains("Duration `99999999999999y` is too long"));
+        assert!(error("pages ~ 3").contains("`~` can only be used with title"));
+        assert!(error("title < 3").contains("Operator `<` can't be used with title"));
+        assert!(error("title ~ \"(\"").contains("Invalid title regex"));
+        assert!(error("pages").contains("Expected `:` or a comparison after `pages`"));
+        assert!(error("tag:a or").contains("Unexpected end of filter"));
+        assert!(error("= 3").contains("Unexpected Op(Eq)"));
+    }
+}
@@ -12,12 +12,20 @@ pub struct Gallery {
     pub tags: Vec<GalleryTag>,
     pub num_favorites: u32,
     pub upload_date: u64,
+    /// Galleries in the "More Like This" section of the gallery page
+    #[serde(skip)]
+    pub related: Vec<u32>,
 }
 
 impl Gallery {
     pub fn pages(&self) -> usize {
         self.images.pages.len()
     }
+
+    /// Checks if the type of some tags is unknown, because the info was saved by an older version
+    pub fn has_untyped_tags(&self) -> bool {
+        self.tags.iter().any(|t| t.kind.is_empty())
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -40,7 +48,7 @@ pub struct GalleryImages {
     pub thumbnail: ImageType,
 }
 
-#[derive(Deserialize, Serialize, Clone, Copy, Debug)]
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
 pub enum ImageType {
     #[serde(rename = "w")]
     Webp,
@@ -51,11 +59,40 @@ pub enum ImageType {
 }
 
 #[derive(Deserialize, Serialize, Debug)]
+#[serde(from = "RawGalleryTag")]
 pub struct GalleryTag {
     pub id: u32,
+    /// Kind of tag (tag, artist, group, parody, character, language, category), empty in the
+    /// gallery.json files of older versions
+    #[serde(rename = "type")]
+    pub kind: String,
     pub name: String,
 }
 
+#[derive(Deserialize)]
+struct RawGalleryTag {
+    id: u32,
+    #[serde(rename = "type", default)]
+    kind: String,
+    name: String,
+    /// Listing page of the tag (e.g. `/artist/name/`)
+    #[serde(default)]
+    url: Option<String>,
+}
+
+impl From<RawGalleryTag> for GalleryTag {
+    fn from(raw: RawGalleryTag) -> Self {
+        let kind = match raw.kind.is_empty() {
+            true => raw.url.as_deref()
+                .and_then(|url| url.trim_matches('/').split('/').next())
+                .unwrap_or_default()
+                .to_string(),
+            false => raw.kind,
+        };
+        Self { id: raw.id, kind, name: raw.name }
+    }
+}
+
 fn num_or_str_num<'de, D>(d: D) -> Result<u32, D::Error>
 where
     D: Deserializer<'de>,
@@ -0,0 +1,171 @@
+use anyhow::{Context, Result};
+
+use crate::ctx;
+
+/// Decodes the body of a JavaScript string literal.
+///
+/// `text` must start right after the opening `quote`, the decoded string is returned together
+/// with the text that follows the closing quote.
+pub fn decode_string_literal(text: &str, quote: char) -> Result<(String, &str)> {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.char_indices().peekable();
+    // High surrogate waiting for its low half
+    let mut pending: Option<u16> = None;
+
+    loop {
+        let (pos, ch) = chars.next()
+            .with_context(ctx!("Unterminated string literal"))?;
+
+        if ch == quote {
+            flush_surrogate(&mut out, &mut pending);
+            return Ok((out, &text[pos + ch.len_utf8()..]));
+        }
+
+        if ch != '\\' {
+            if ch == '\n' || ch == '\r' {
+                anyhow::bail!("Unescaped line terminator in string literal at byte {pos}");
+            }
+            flush_surrogate(&mut out, &mut pending);
+            out.push(ch);
+            continue;
+        }
+
+        let (_, escape) = chars.next()
+            .with_context(ctx!("Unterminated escape sequence in string literal"))?;
+
+        let unit = match escape {
+            'u' if chars.next_if(|&(_, c)| c == '{').is_some() => {
+                let mut code = 0u32;
+                let mut digits = 0;
+                loop {
+                    let (_, c) = chars.next()
+                        .with_context(ctx!("Unterminated \\u{{}} escape in string literal"))?;
+                    if c == '}' { break; }
+                    let digit = c.to_digit(16)
+                        .with_context(ctx!("Invalid hex digit `{c}` in \\u{{}} escape at byte {pos}"))?;
+                    code = code * 16 + digit;
+                    if code > 0x10FFFF {
+                        anyhow::bail!("Code point out of range in \\u{{}} escape at byte {pos}");
+                    }
+                    digits += 1;
+                }
+                if digits == 0 {
+                    anyhow::bail!("Empty \\u{{}} escape at byte {pos}");
+                }
+                code
+            }
+            'u' => read_hex(&mut chars, 4)
+                .with_context(ctx!("Invalid \\u escape at byte {pos}"))?,
+            'x' => read_hex(&mut chars, 2)
+                .with_context(ctx!("Invalid \\x escape at byte {pos}"))?,
+            'b' => 0x08,
+            'f' => 0x0C,
+            'n' => 0x0A,
+            'r' => 0x0D,
+            't' => 0x09,
+            'v' => 0x0B,
+            // `\08` and `\09` are a NUL followed by the digit, not octal escapes
+            '0' if !chars.peek().is_some_and(|(_, c)| matches!(c, '0'..='7')) => 0x00,
+            '0'..='7' => anyhow::bail!("Octal escape sequences are not supported, at byte {pos}"),
+            // Line continuations produce nothing
+            '\r' => {
+                chars.next_if(|&(_, c)| c == '\n');
+                continue;
+            }
+            '\n' | '\u{2028}' | '\u{2029}' => continue,
+            // Every other escaped character (`\"`, `\'`, `\\`, `\/`, `\8`, ...) stands for itself
+            other => other as u32,
+        };
+
+        push_code_unit(&mut out, &mut pending, unit);
+    }
+}
+
+fn read_hex(chars: &mut impl Iterator<Item = (usize, char)>, len: usize) -> Option<u32> {
+    (0..len).try_fold(0, |acc, _| {
+        let (_, ch) = chars.next()?;
+        Some(acc * 16 + ch.to_digit(16)?)
+    })
+}
+
+/// Pushes a value produced by an escape, joining UTF-16 surrogate pairs.
+///
+/// Lone surrogates can't be represented in a rust string, so they are replaced with U+FFFD.
+fn push_code_unit(out: &mut String, pending: &mut Option<u16>, unit: u32) {
+    match unit {
+        0xD800..=0xDBFF => {
+            flush_surrogate(out, pending);
+            *pending = Some(unit as u16);
+        }
+        0xDC00..=0xDFFF => match pending.take() {
+            Some(high) => {
+                let code = 0x10000 + ((high as u32 - 0xD800) << 10) + (unit - 0xDC00);
+                out.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+            }
+            None => out.push(char::REPLACEMENT_CHARACTER),
+        },
+        _ => {
+            flush_surrogate(out, pending);
+            out.push(char::from_u32(unit).unwrap_or(char::REPLACEMENT_CHARACTER));
+        }
+    }
+}
+
+fn flush_surrogate(out: &mut String, pending: &mut Option<u16>) {
+    if pending.take().is_some() {
+        out.push(char::REPLACEMENT_CHARACTER);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::decode_string_literal;
+
+    fn decode(text: &str) -> String {
+        let (decoded, _) = decode_string_literal(text, '"').unwrap();
+        decoded
+    }
+
+    #[test]
+    fn simple_escapes() {
+        assert_eq!(decode(r#"a\"b\\c\/d\'e""#), r#"a"b\c/d'e"#);
+        assert_eq!(decode(r#"\x41\u{1F600}\u0041""#), "A\u{1F600}A");
+        assert_eq!(decode(r#"\n\t\0""#), "\n\t\0");
+    }
+
+    #[test]
+    fn rest_after_quote() {
+        let (decoded, rest) = decode_string_literal(r#"ab");more"#, '"').unwrap();
+        assert_eq!(decoded, "ab");
+        assert_eq!(rest, ");more");
+
+        let (decoded, _) = decode_string_literal(r#"a"b'"#, '\'').unwrap();
+        assert_eq!(decoded, "a\"b");
+    }
+
+    #[test]
+    fn surrogates() {
+        assert_eq!(decode(r#"😀""#), "\u{1F600}");
+        assert_eq!(decode(r#"a\ud83db""#), "a\u{FFFD}b");
+        assert_eq!(decode(r#"a\ude00b""#), "a\u{FFFD}b");
+        assert_eq!(decode(r#"\ud83d""#), "\u{FFFD}");
+        assert_eq!(decode(r#"\ud83d😀""#), "\u{FFFD}\u{1F600}");
+    }
+
+    #[test]
+    fn invalid_literals() {
+        for text in [r#"abc"#, r#"abc\"#, r#"\u12"#, r#"\u{}""#, r#"\u{110000}""#, "a\nb\""] {
+            assert!(decode_string_literal(text, '"').is_err(), "{text:?} should not decode");
+        }
+    }
+
+    #[test]
+    fn octal_escapes() {
+        for text in [r#"\1""#, r#"\01""#, r#"\00""#, r#"\07""#] {
+            assert!(decode_string_literal(text, '"').is_err(), "{text:?} should not decode");
+        }
+        assert_eq!(decode(r#"\0a""#), "\0a");
+        assert_eq!(decode(r#"\08\09""#), "\08\09");
+        assert_eq!(decode(r#"\8\9""#), "89");
+    }
+}
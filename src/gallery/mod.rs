@@ -4,14 +4,93 @@ use std::path::Path;
 use anyhow::{Context, Result};
 use futures::{stream, StreamExt};
 use rand::Rng;
-use reqwest::Client;
+use reqwest::{Client, Url};
 use scraper::{Html, Selector};
 use tokio::{fs as fs, io::AsyncWriteExt};
 
-use crate::ctx;
+use crate::{ctx, http};
+use crate::query::QueryInfo;
 
 mod format;
 pub use format::*;
+mod js;
+
+/// What to do with the pages of a downloaded gallery that was uploaded again with different pages
+#[derive(clap::ValueEnum, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum UpdatePolicy {
+    /// Move the old pages and info to `versions/<timestamp>` in the gallery directory
+    #[default]
+    Versioned,
+    /// Delete the old pages
+    Replace,
+}
+
+/// Pages of a gallery to download, numbered from 1 (e.g. `1-5,12,20-`)
+#[derive(Clone, Debug)]
+pub struct PageSelection {
+    /// Inclusive ranges, without an end they go to the last page
+    ranges: Vec<(usize, Option<usize>)>,
+}
+
+/// Outcome of [`Gallery::download`]
+#[derive(Default)]
+pub struct DownloadReport {
+    /// Pages that were checked, whether they were downloaded or already present
+    pub pages: Vec<usize>,
+    /// Pages that couldn't be downloaded, with their error
+    pub failed: Vec<(usize, String)>,
+    /// Number of pages fetched from the image servers, and their size
+    pub downloaded: usize,
+    pub bytes: u64,
+}
+
+impl std::str::FromStr for PageSelection {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> Result<Self> {
+        let parse = |n: &str| -> Result<usize> {
+            match n.trim().parse() {
+                Ok(0) => anyhow::bail!("Pages are numbered from 1"),
+                Ok(n) => Ok(n),
+                Err(_) => anyhow::bail!("Invalid page `{n}`"),
+            }
+        };
+        let ranges = text.split(',')
+            .map(|range| {
+                let (start, end) = match range.split_once('-') {
+                    Some((start, "")) => (parse(start)?, None),
+                    Some(("", end)) => (1, Some(parse(end)?)),
+                    Some((start, end)) => (parse(start)?, Some(parse(end)?)),
+                    None => (parse(range)?, Some(parse(range)?)),
+                };
+                if end.is_some_and(|end| end < start) {
+                    anyhow::bail!("Range start must not be bigger than its end in `{range}`");
+                }
+                Ok((start, end))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { ranges })
+    }
+}
+
+impl PageSelection {
+    pub fn from_pages(pages: impl IntoIterator<Item = usize>) -> Self {
+        Self { ranges: pages.into_iter().map(|page| (page, Some(page))).collect() }
+    }
+
+    /// Checks that every selected page is in a gallery with `pages` pages
+    pub fn check(&self, pages: usize) -> Result<()> {
+        let last = self.ranges.iter().map(|&(start, end)| end.unwrap_or(start)).max().unwrap_or(0);
+        if last > pages {
+            anyhow::bail!("Page {last} is out of range, the gallery has {pages} pages");
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, page: usize) -> bool {
+        self.ranges.iter().any(|&(start, end)| start <= page && end.is_none_or(|end| page <= end))
+    }
+}
 
 impl ImageType {
     fn extension(self) -> &'static str {
@@ -23,58 +102,182 @@ impl ImageType {
     }
 }
 
-fn replace_unicode_escapes(mut text: &str) -> String {
-    let mut out = String::new();
-    while let Some((lhs, rhs)) = text.split_once("\\u") {
-        out.push_str(lhs);
-        let Some((code, rhs)) = rhs.split_at_checked(4) else {
-            text = rhs;
-            out.push_str("\\u");
-            continue;
-        };
+impl Gallery {
+    pub async fn load(client: &Client, site: &Url, id: u32) -> Result<Self> {
+        let url = site.join(&format!("g/{id}/"))
+            .with_context(ctx!("What? Invalid gallery url for {id}"))?;
+        let url = url.as_str();
+        log::trace!("Connecting to gallery: {url}");
+
+        let res = http::get(client, url).await
+            .with_context(ctx!("Failed to retrive gallery at {url}"))?;
+        let text = http::text(res, url).await
+            .with_context(ctx!("Failed to read text at {url}"))?;
+
+        let gallery = Self::from_page(&text)?;
+        log::trace!("Gallery {id} has {} related galleries", gallery.related.len());
+        Ok(gallery)
+    }
 
-        let code = code.chars()
-            .map(|ch| ch.to_digit(16))
-            .try_fold(0, |acc, digit| Some(acc * 16 + digit?));
+    /// Parses the info of a gallery from the html of its page
+    pub fn from_page(text: &str) -> Result<Self> {
+        let document = Html::parse_document(text);
 
-        match code.and_then(|c| char::from_u32(c)) {
-            Some(ch) => { out.push(ch); }
-            None => { out.push_str("\\u"); }
+        let selector = Selector::parse("body > script").unwrap();
+        let script = document.select(&selector)
+            .map(|s| s.inner_html())
+            .find(|s| s.trim().starts_with("window._gallery = JSON.parse("))
+            .with_context(ctx!("Failed to find gallery json info"))?;
+
+        let literal = script.trim()
+            .strip_prefix("window._gallery = JSON.parse(")
+            .unwrap()
+            .trim_start();
+        let quote = literal.chars().next()
+            .filter(|ch| matches!(ch, '"' | '\''))
+            .with_context(ctx!("What? Gallery json info is not a string literal"))?;
+        let (json, _) = js::decode_string_literal(&literal[1..], quote)
+            .with_context(ctx!("Failed to decode gallery json info string"))?;
+
+        let mut gallery: Self = serde_json::from_str(&json)
+            .with_context(ctx!("Failed to parse gallery json info"))?;
+
+        let related_selector = Selector::parse("#related-container a.cover").unwrap();
+        gallery.related = document.select(&related_selector)
+            .filter_map(|a| a.attr("href"))
+            .filter_map(|href| QueryInfo::parse_gallery_path(href).ok())
+            .collect();
+
+        Ok(gallery)
+    }
+
+    /// Reads the info of the galleries downloaded to the output directory
+    pub fn read_library(out_path: &Path) -> Result<Vec<Self>> {
+        let entries = std::fs::read_dir(out_path)
+            .with_context(ctx!("Cannot read output directory {out_path:?}"))?;
+
+        let mut galleries = Vec::new();
+        for entry in entries.filter_map(|e| e.ok()) {
+            let info_path = entry.path().join("gallery.json");
+            let text = match std::fs::read_to_string(&info_path) {
+                Ok(text) => text,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => {
+                    log::warn!("Cannot read gallery info {info_path:?}\nError: {e}");
+                    continue;
+                }
+            };
+            match serde_json::from_str(&text) {
+                Ok(gallery) => galleries.push(gallery),
+                Err(e) => log::warn!("Invalid gallery info {info_path:?}\nError: {e}"),
+            }
         }
+        galleries.sort_by_key(|g: &Self| g.id);
+        Ok(galleries)
+    }
+
+    /// Differences in the info of two versions of a gallery, one line each (e.g. `favorites: 10 -> 12`)
+    pub fn changes(&self, new: &Self) -> Vec<String> {
+        let mut changes = Vec::new();
+        let mut compare = |field: &str, old: String, new: String| {
+            if old != new {
+                changes.push(format!("{field}: {old} -> {new}"));
+            }
+        };
+        compare("media_id", self.media_id.clone(), new.media_id.clone());
+        compare("title", self.title.pretty.clone(), new.title.pretty.clone());
+        compare("english_title", self.title.english.clone(), new.title.english.clone());
+        compare("japanese_title", self.title.japanese.clone(), new.title.japanese.clone());
+        compare("pages", self.pages().to_string(), new.pages().to_string());
+        compare("favorites", self.num_favorites.to_string(), new.num_favorites.to_string());
+        compare("uploaded", self.upload_date.to_string(), new.upload_date.to_string());
 
-        text = rhs;
+        // Without the types of the old tags only the names can be compared
+        let untyped = self.has_untyped_tags();
+        if untyped && !new.has_untyped_tags() {
+            changes.push("tag types: added".to_string());
+        }
+        let tag_names = |gallery: &Self| -> Vec<String> {
+            gallery.tags.iter()
+                .map(|t| match untyped {
+                    true => t.name.clone(),
+                    false => format!("{}:{}", t.kind, t.name),
+                })
+                .collect()
+        };
+        let (old_tags, new_tags) = (tag_names(self), tag_names(new));
+        changes.extend(new_tags.iter().filter(|t| !old_tags.contains(t)).map(|t| format!("+ {t}")));
+        changes.extend(old_tags.iter().filter(|t| !new_tags.contains(t)).map(|t| format!("- {t}")));
+        changes
     }
-    out.push_str(text);
-    out
-}
 
-impl Gallery {
-    pub async fn load(client: &Client, id: u32) -> Result<Self> {
-        let url = format!("https://nhentai.net/g/{id}");
-        log::trace!("Connecting to gallery: {url}");
+    /// Reads the info file of a downloaded gallery, `None` if it wasn't downloaded
+    pub fn read_info(out_path: &Path, id: u32) -> Result<Option<Self>> {
+        let info_path = out_path.join(id.to_string()).join("gallery.json");
+        let text = match std::fs::read_to_string(&info_path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(ctx!("Cannot read gallery info {info_path:?}")),
+        };
+        serde_json::from_str(&text)
+            .map(Some)
+            .with_context(ctx!("Invalid gallery info {info_path:?}"))
+    }
 
-        let text = client.get(&url)
-            .send().await
-            .with_context(ctx!("Failed to retrive gallery at {url}"))?
-            .error_for_status()
-            .with_context(ctx!("Received error from nhentai at {url}"))?
-            .text().await
-            .with_context(ctx!("Failed to read text at {url}"))?;
+    /// Checks if the pages of `self` are different from the ones of an older version of the gallery
+    pub fn pages_changed(&self, old: &Self) -> bool {
+        self.media_id != old.media_id || self.images.pages != old.images.pages
+    }
 
-        let document = Html::parse_document(&text);
+    /// Makes room for a new version of a downloaded gallery, `old` is the info of the downloaded one
+    pub fn remove_version(out_path: &Path, old: &Self, policy: UpdatePolicy) -> Result<()> {
+        let dir = out_path.join(old.id.to_string());
+        let mut files: Vec<String> = old.images.pages.iter()
+            .enumerate()
+            .map(|(i, ext)| format!("{}.{}", i + 1, ext.extension()))
+            .collect();
+        files.push("gallery.json".to_string());
 
-        let selector = Selector::parse("body > script").unwrap();
-        let json = document.select(&selector)
-            .find_map(|s| {
-                let inner = s.inner_html();
-                let json = inner.trim().strip_prefix("window._gallery = JSON.parse(\"")?;
-                let (json, _) = json.split_once('"')?;
-                Some(replace_unicode_escapes(&json))
-            })
-            .with_context(ctx!("Failed to find gallery json info"))?;
+        let version_dir = dir.join("versions").join(crate::library::now().to_string());
+        if policy == UpdatePolicy::Versioned {
+            std::fs::create_dir_all(&version_dir)
+                .with_context(ctx!("Cannot create directory {version_dir:?}"))?;
+        }
+        for file in files {
+            let path = dir.join(&file);
+            let res = match policy {
+                UpdatePolicy::Versioned => std::fs::rename(&path, version_dir.join(&file)),
+                UpdatePolicy::Replace if file == "gallery.json" => continue,
+                UpdatePolicy::Replace => std::fs::remove_file(&path),
+            };
+            match res {
+                Err(e) if e.kind() != ErrorKind::NotFound => {
+                    return Err(e).with_context(ctx!("Cannot remove old page {path:?}"));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
 
-        serde_json::from_str(&json)
-            .with_context(ctx!("Failed to parse gallery json info"))
+    /// Rewrites the info file of a downloaded gallery, the old one is kept as `gallery.prev.json`
+    pub fn replace_info(&self, out_path: &Path) -> Result<()> {
+        let dir = out_path.join(self.id.to_string());
+        let info_path = dir.join("gallery.json");
+        let prev_path = dir.join("gallery.prev.json");
+
+        let json = serde_json::to_vec_pretty(self)
+            .with_context(ctx!("Cannot serialize gallery info"))?;
+        std::fs::rename(&info_path, &prev_path)
+            .with_context(ctx!("Cannot move {info_path:?} to {prev_path:?}"))?;
+        std::fs::write(&info_path, json)
+            .with_context(ctx!("Cannot write gallery info {info_path:?}"))
+    }
+
+    /// Checks if a gallery was already downloaded to the output directory, using its info file
+    pub async fn is_downloaded(out_path: &Path, id: u32) -> bool {
+        let info_path = out_path.join(id.to_string()).join("gallery.json");
+        fs::try_exists(&info_path).await.unwrap_or(false)
     }
 
     async fn serialize_self(&self, out_path: &Path) {
@@ -93,18 +296,98 @@ impl Gallery {
         };
     }
 
+    /// Url of a page on a random image server, or on `images` if given, `filename` is in the
+    /// `{index}.{extension}` format
+    fn page_url(&self, images: Option<&Url>, filename: &str) -> String {
+        let path = format!("galleries/{}/{filename}", self.media_id);
+        match images.and_then(|url| url.join(&path).ok()) {
+            Some(url) => url.into(),
+            None => {
+                let server_no = rand::rng().random_range(1..=4);
+                format!("https://i{server_no}.nhentai.net/{path}")
+            }
+        }
+    }
+
+    /// Pages (numbered from 1) that [`Gallery::download`] would download with the same options
+    pub async fn missing_pages(
+        &self,
+        out_path: &Path,
+        overwrite: bool,
+        check_missing: bool,
+        selection: Option<&PageSelection>,
+    ) -> Vec<usize> {
+        let out_path = out_path.join(self.id.to_string());
+        let all = (1..=self.images.pages.len())
+            .filter(|&i| selection.is_none_or(|s| s.contains(i)))
+            .collect();
+
+        match fs::metadata(&out_path).await {
+            Ok(m) if m.file_type().is_dir() => {}
+            _ => return all,
+        }
+        if !check_missing {
+            return Vec::new();
+        }
+        if overwrite || !fs::try_exists(out_path.join("gallery.json")).await.unwrap_or(false) {
+            return all;
+        }
+
+        let mut missing = Vec::new();
+        for (i, ext) in self.images.pages.iter().enumerate() {
+            if selection.is_some_and(|s| !s.contains(i + 1)) {
+                continue;
+            }
+            let path = out_path.join(format!("{}.{}", i + 1, ext.extension()));
+            if !fs::try_exists(&path).await.unwrap_or(false) {
+                missing.push(i + 1);
+            }
+        }
+        missing
+    }
+
+    /// Estimates the size of `pages` from the size of a few of them, given by HEAD requests.
+    /// Returns `None` if the size of none of the sampled pages is known.
+    pub async fn estimate_size(&self, client: &Client, images: Option<&Url>, pages: &[usize]) -> Option<u64> {
+        const SAMPLES: usize = 3;
+
+        // Evenly spaced samples, covers and inner pages can have very different sizes
+        let step = pages.len().div_ceil(SAMPLES).max(1);
+        let mut sizes = Vec::new();
+        for &index in pages.iter().step_by(step) {
+            let filename = format!("{index}.{}", self.images.pages[index - 1].extension());
+            let url = self.page_url(images, &filename);
+            log::trace!("Requesting size of page #{index} from gallery: {} url: {url}", self.id);
+
+            let res = client.head(&url)
+                .send().await
+                .and_then(|r| r.error_for_status());
+            match res.map(|r| r.content_length()) {
+                Ok(Some(size)) => sizes.push(size),
+                Ok(None) => log::debug!("No size for page #{index} from gallery: {}", self.id),
+                Err(e) => log::debug!("Failed to get size of page #{index} from gallery: {}\nError: {e}", self.id),
+            }
+        }
+
+        if sizes.is_empty() {
+            return None;
+        }
+        let average = sizes.iter().sum::<u64>() / sizes.len() as u64;
+        Some(average * pages.len() as u64)
+    }
+
+    /// Downloads a page, returns its size or `None` if it was already present
     async fn download_page(
         &self,
-        extension: &str,
         index: usize,
         out_path: &Path,
         client: &Client,
+        images: Option<&Url>,
         overwrite: bool,
         gallery_exists: bool,
-    ) -> Result<()> {
-        let server_no = rand::rng().random_range(1..=4);
-        let filename = format!("{index}.{extension}");
-        let url = format!("https://i{server_no}.nhentai.net/galleries/{}/{filename}", self.media_id);
+    ) -> Result<Option<u64>> {
+        let filename = format!("{index}.{}", self.images.pages[index - 1].extension());
+        let url = self.page_url(images, &filename);
         let path = out_path.join(filename);
 
         log::trace!("Downloading page #{index} from gallery: {} url: {url} path: {path:?}", self.id);
@@ -112,7 +395,7 @@ impl Gallery {
         if !overwrite && gallery_exists {
             if let Ok(true) = fs::try_exists(&path).await {
                 log::trace!("Not downloading page #{index} from gallery: {} because it exists", self.id);
-                return Ok(())
+                return Ok(None)
             } else {
                 log::info!("Downloading missing page #{index} for gallery: {}", self.id);
             }
@@ -131,21 +414,24 @@ impl Gallery {
 
         file.write_all(&bytes).await
             .with_context(ctx!("Failed to write to file: {path:?}"))
-            .inspect_err(|_| _ = fs::remove_file(&path))
+            .inspect_err(|_| _ = fs::remove_file(&path))?;
+        Ok(Some(bytes.len() as u64))
     }
 
     pub async fn download(&self,
         client: &Client,
+        images: Option<&Url>,
         out_path: &Path,
         overwrite: bool,
-        check_missing: bool
-    ) -> Result<()> {
+        check_missing: bool,
+        selection: Option<&PageSelection>,
+    ) -> Result<DownloadReport> {
         let out_path = out_path.join(self.id.to_string());
 
         let exists = match fs::metadata(&out_path).await {
             Err(e) if e.kind() == ErrorKind::NotFound => false,
             Ok(m) if m.file_type().is_dir() => true,
-            e @ Err(_) => { return e.with_context(ctx!("Cannot read gallery directory {out_path:?}")).map(|_| ()); }
+            e @ Err(_) => { return e.with_context(ctx!("Cannot read gallery directory {out_path:?}")).map(|_| Default::default()); }
             Ok(_) => anyhow::bail!("Cannot create gallery directory {out_path:?} a file is already present")
         };
 
@@ -154,24 +440,132 @@ impl Gallery {
                 .with_context(ctx!("Failed to create gallery directory {out_path:?}"))?;
         } else if !check_missing {
             log::debug!("Gallery folder {out_path:?} is already present do not attempt to download missing pages (check_missing == false)");
-            return Ok(());
+            return Ok(DownloadReport::default());
         }
 
         let gallery_info_path = out_path.join("gallery.json");
         let gallery_info_exists = fs::try_exists(&gallery_info_path).await.unwrap_or(false);
         self.serialize_self(&gallery_info_path).await;
 
-        stream::iter(self.images.pages.iter().enumerate())
-            .for_each_concurrent(5, async |(i, ext)| {
-                let i = i + 1;
-                let res = self.download_page(ext.extension(), i, &out_path, client, overwrite, gallery_info_exists).await;
-                if let Err(e) = res {
+        let pages: Vec<usize> = (1..=self.pages())
+            .filter(|&i| selection.is_none_or(|s| s.contains(i)))
+            .collect();
+        let results: Vec<(usize, Result<Option<u64>>)> = stream::iter(pages.iter().copied())
+            .map(async |i| (i, self.download_page(i, &out_path, client, images, overwrite, gallery_info_exists).await))
+            .buffer_unordered(5)
+            .collect()
+            .await;
+
+        let mut report = DownloadReport { pages, ..Default::default() };
+        for (i, res) in results {
+            match res {
+                Ok(Some(bytes)) => {
+                    report.downloaded += 1;
+                    report.bytes += bytes;
+                }
+                Ok(None) => {}
+                Err(e) => {
                     log::warn!("Couldn't download page #{i} from gallery {}", self.id);
                     log::warn!("Error: {e}");
+                    report.failed.push((i, format!("{e:#}")));
                 }
-            })
-            .await;
+            }
+        }
+        report.failed.sort_by_key(|&(i, _)| i);
+        Ok(report)
+    }
+}
 
-        Ok(())
+#[cfg(test)]
+mod tests {
+    use super::{Gallery, ImageType};
+
+    const ESCAPES: &str = include_str!("../../tests/fixtures/gallery/escapes.html");
+    const EMOJI: &str = include_str!("../../tests/fixtures/gallery/emoji.html");
+    const LONE_SURROGATE: &str = include_str!("../../tests/fixtures/gallery/lone_surrogate.html");
+    const TRUNCATED: &str = include_str!("../../tests/fixtures/gallery/truncated.html");
+
+    #[test]
+    fn escaped_quotes_and_slashes() {
+        let gallery = Gallery::from_page(ESCAPES).unwrap();
+        assert_eq!(gallery.id, 421337);
+        assert_eq!(gallery.media_id, "2290118");
+        assert_eq!(gallery.title.english, r#"The "Quoted" Title \ With Backslash"#);
+        assert_eq!(gallery.title.pretty, r#"The "Quoted" Title"#);
+        assert_eq!(gallery.images.pages, [ImageType::Jpg, ImageType::Jpg, ImageType::Png, ImageType::Webp]);
+        assert_eq!(gallery.num_favorites, 1523);
+        assert_eq!(gallery.upload_date, 1664402861);
+        assert_eq!(gallery.related, [421001, 421002, 421003]);
+
+        let tags: Vec<_> = gallery.tags.iter().map(|t| format!("{}:{}", t.kind, t.name)).collect();
+        assert_eq!(tags, [
+            "language:english",
+            "language:translated",
+            "tag:full color",
+            "artist:some artist",
+            "category:doujinshi",
+        ]);
+    }
+
+    #[test]
+    fn surrogate_pairs() {
+        let gallery = Gallery::from_page(EMOJI).unwrap();
+        assert_eq!(gallery.id, 398120);
+        assert_eq!(gallery.title.english, "Smile \u{1F600} Again");
+        assert_eq!(gallery.title.japanese, "笑顔 \u{1F600} もう一度");
+        assert_eq!(gallery.pages(), 3);
+        assert_eq!(gallery.related, [398100]);
+    }
+
+    #[test]
+    fn lone_surrogates() {
+        let gallery = Gallery::from_page(LONE_SURROGATE).unwrap();
+        // The id is a string in this page
+        assert_eq!(gallery.id, 300001);
+        assert_eq!(gallery.title.english, "");
+        assert_eq!(gallery.title.japanese, "壊れた\u{FFFD}タイトル");
+        assert!(gallery.related.is_empty());
+    }
+
+    /// The fixtures are written by hand, this checks the parser against the markup of the site
+    #[tokio::test]
+    #[ignore = "needs access to nhentai.net"]
+    async fn live_page() {
+        let site = reqwest::Url::parse("https://nhentai.net/").unwrap();
+        let gallery = Gallery::load(&reqwest::Client::new(), &site, 177013).await.unwrap();
+        assert_eq!(gallery.id, 177013);
+        assert!(!gallery.title.pretty.is_empty());
+        assert!(gallery.pages() > 0);
+        assert!(gallery.tags.iter().all(|t| !t.kind.is_empty()));
+        assert!(!gallery.related.is_empty());
+    }
+
+    #[test]
+    fn untyped_tags() {
+        // gallery.json saved by older versions, the second tag also has its url
+        let info = r#"{
+            "id": 177013, "media_id": "987654",
+            "title": { "english": "Old", "japanese": null, "pretty": "Old" },
+            "images": { "pages": [{ "t": "j" }], "cover": { "t": "j" }, "thumbnail": { "t": "j" } },
+            "tags": [
+                { "id": 1, "name": "full color" },
+                { "id": 2, "name": "someone", "url": "/artist/someone/" }
+            ],
+            "num_favorites": 10, "upload_date": 1476793729
+        }"#;
+        let old: Gallery = serde_json::from_str(info).unwrap();
+        assert_eq!(old.tags[0].kind, "");
+        assert_eq!(old.tags[1].kind, "artist");
+        assert!(old.has_untyped_tags());
+
+        let new = Gallery::from_page(ESCAPES).unwrap();
+        assert!(!new.has_untyped_tags());
+        assert!(old.changes(&new).contains(&"tag types: added".to_string()));
+    }
+
+    #[test]
+    fn unterminated_literal() {
+        let e = Gallery::from_page(TRUNCATED).unwrap_err();
+        assert!(format!("{e:#}").contains("Unterminated string literal"), "{e:#}");
     }
 }
@@ -0,0 +1,235 @@
+use std::fmt;
+
+use anyhow::{Context, Result};
+
+use reqwest::{Client, Response, StatusCode, Url};
+use reqwest::header::HeaderMap;
+
+use crate::ctx;
+
+/// Proxy configuration for a class of hosts
+#[derive(Clone, Debug)]
+pub enum ProxySetting {
+    Direct,
+    Url(Url),
+}
+
+/// Parses a proxy url, or `direct` for no proxy
+pub fn parse_proxy(text: &str) -> Result<ProxySetting> {
+    if text.eq_ignore_ascii_case("direct") {
+        return Ok(ProxySetting::Direct);
+    }
+
+    let url = Url::parse(text)
+        .with_context(ctx!("Invalid proxy url `{text}`"))?;
+    match url.scheme() {
+        "http" | "https" | "socks5" | "socks5h" => Ok(ProxySetting::Url(url)),
+        scheme => anyhow::bail!("Unsupported proxy scheme `{scheme}`, use http, https, socks5 or socks5h"),
+    }
+}
+
+/// Proxy set in the environment for a url, from `HTTPS_PROXY`, `HTTP_PROXY` or `ALL_PROXY` (also
+/// lower case) unless the host is in `NO_PROXY`, as reqwest does when no proxy is configured
+pub fn env_proxy(url: &Url) -> Option<Url> {
+    let var = |name: &str| {
+        std::env::var(name).or_else(|_| std::env::var(name.to_lowercase())).ok()
+            .filter(|value| !value.trim().is_empty())
+    };
+    let host = url.host_str()?;
+
+    let excluded = var("NO_PROXY").is_some_and(|no_proxy| {
+        no_proxy.split(',').map(str::trim).filter(|e| !e.is_empty()).any(|entry| {
+            let domain = entry.trim_start_matches('*').trim_start_matches('.');
+            entry == "*" || host == domain || host.ends_with(&format!(".{domain}"))
+        })
+    });
+    if excluded {
+        return None;
+    }
+
+    let scheme_var = match url.scheme() {
+        "https" => "HTTPS_PROXY",
+        _ => "HTTP_PROXY",
+    };
+    let proxy = var(scheme_var).or_else(|| var("ALL_PROXY"))?;
+    Url::parse(&proxy)
+        .or_else(|_| Url::parse(&format!("http://{proxy}")))
+        .inspect_err(|e| log::warn!("Ignoring invalid proxy `{proxy}` from the environment\nError: {e}"))
+        .ok()
+}
+
+/// Checks if the host is one of the image servers (`i{n}.nhentai.net` or `t{n}.nhentai.net`)
+pub fn is_cdn_host(host: &str) -> bool {
+    let Some(sub) = host.strip_suffix(".nhentai.net") else {
+        return false;
+    };
+    sub.strip_prefix(['i', 't'])
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Kind of page served in place of the requested one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockKind {
+    /// Cloudflare (or similar) browser check
+    Challenge,
+    /// The page is only available to logged in users
+    LoginRequired,
+    /// The site is down for maintenance
+    Maintenance,
+}
+
+/// Error returned when the site doesn't serve the requested page.
+///
+/// When this happens every following request will most likely fail in the same way, so it should
+/// stop the whole run instead of being logged and skipped.
+#[derive(Debug)]
+pub struct BlockedError {
+    pub kind: BlockKind,
+    pub url: String,
+}
+
+impl fmt::Display for BlockedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let url = &self.url;
+        match self.kind {
+            BlockKind::Challenge => write!(f,
+                "nhentai served a browser challenge page instead of {url}\n\
+                Open the site in a browser and complete the check, then export its cookies \
+                (`cf_clearance` is required) and pass them with `--cookies <FILE>` together with \
+                the exact User-Agent of that browser with `--user-agent <UA>`"
+            ),
+            BlockKind::LoginRequired => write!(f,
+                "nhentai requires a login to access {url}\n\
+                Log in from a browser, export its cookies (`sessionid` is required) and pass them \
+                with `--cookies <FILE>`"
+            ),
+            BlockKind::Maintenance => write!(f,
+                "nhentai is down for maintenance, cannot access {url}\n\
+                Try again later"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BlockedError {}
+
+/// Checks if an error (or one of its causes) is a [`BlockedError`]
+pub fn is_blocked(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<BlockedError>().is_some()
+}
+
+/// Checks if an error (or one of its causes) is a 404 reply, e.g. for a deleted gallery
+pub fn is_not_found(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|e| e.downcast_ref::<reqwest::Error>())
+        .any(|e| e.status() == Some(StatusCode::NOT_FOUND))
+}
+
+fn blocked_by_headers(headers: &HeaderMap) -> Option<BlockKind> {
+    headers.get("cf-mitigated")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.eq_ignore_ascii_case("challenge"))
+        .then_some(BlockKind::Challenge)
+}
+
+fn blocked_by_markup(text: &str) -> Option<BlockKind> {
+    const CHALLENGE_MARKERS: &[&str] = &[
+        "cf-browser-verification",
+        "window._cf_chl_opt",
+        "cf_chl_prog",
+        "cf-error-details",
+    ];
+
+    let title = text.split_once("<title>")
+        .and_then(|(_, rhs)| rhs.split_once("</title>"))
+        .map(|(title, _)| title.trim().to_lowercase())
+        .unwrap_or_default();
+
+    if title == "just a moment..." || title == "attention required! | cloudflare"
+        || CHALLENGE_MARKERS.iter().any(|m| text.contains(m)) {
+        Some(BlockKind::Challenge)
+    } else if text.contains("id=\"login-container\"") {
+        // Some pages serve the login form directly instead of redirecting to it, the title can't
+        // be used as it's the same of a gallery named "Login"
+        Some(BlockKind::LoginRequired)
+    } else if title.contains("maintenance") && !title.contains("nhentai: hentai doujinshi") {
+        Some(BlockKind::Maintenance)
+    } else {
+        None
+    }
+}
+
+fn blocked_by_location(res: &Response) -> Option<BlockKind> {
+    let path = res.url().path().trim_matches('/');
+    (path == "login" || path.starts_with("login/")).then_some(BlockKind::LoginRequired)
+}
+
+/// Sends a GET request to the site, failing with a [`BlockedError`] when a challenge, login or
+/// maintenance page is served instead of the requested one.
+///
+/// Successful and redirect responses are returned as they are, use [`text`] to read them.
+pub async fn get(client: &Client, url: &str) -> Result<Response> {
+    let res = client.get(url)
+        .send().await
+        .with_context(ctx!("Failed to connect to {url}"))?;
+
+    let status = res.status();
+    let kind = blocked_by_headers(res.headers())
+        .or_else(|| blocked_by_location(&res));
+    if let Some(kind) = kind {
+        return Err(BlockedError { kind, url: url.to_string() }.into());
+    }
+
+    let Err(err) = res.error_for_status_ref() else {
+        return Ok(res);
+    };
+
+    let text = res.text().await.unwrap_or_default();
+    let kind = blocked_by_markup(&text)
+        .or((status == StatusCode::SERVICE_UNAVAILABLE).then_some(BlockKind::Maintenance));
+    if let Some(kind) = kind {
+        return Err(BlockedError { kind, url: url.to_string() }.into());
+    }
+
+    Err(err).with_context(ctx!("Received error from nhentai at {url}"))
+}
+
+/// Reads the body of a response returned by [`get`], checking the markup for block pages
+pub async fn text(res: Response, url: &str) -> Result<String> {
+    let text = res.text().await
+        .with_context(ctx!("Failed to read page contents at {url}"))?;
+
+    if let Some(kind) = blocked_by_markup(&text) {
+        return Err(BlockedError { kind, url: url.to_string() }.into());
+    }
+
+    Ok(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockKind, blocked_by_markup};
+
+    const LOGIN: &str = include_str!("../tests/fixtures/favorites/login.html");
+    const FAVORITES: &str = include_str!("../tests/fixtures/favorites/page1.html");
+    const ATTENTION_TITLE: &str = include_str!("../tests/fixtures/gallery/attention_title.html");
+    const LOGIN_TITLE: &str = include_str!("../tests/fixtures/gallery/login_title.html");
+
+    #[test]
+    fn login_page() {
+        assert_eq!(blocked_by_markup(LOGIN), Some(BlockKind::LoginRequired));
+        assert_eq!(blocked_by_markup(FAVORITES), None);
+        // Gallery titles are not login pages
+        assert_eq!(blocked_by_markup(LOGIN_TITLE), None);
+    }
+
+    #[test]
+    fn challenge_page() {
+        let page = "<!DOCTYPE html><html><head><title>Attention Required! | Cloudflare</title></head>\
+            <body><div id=\"cf-wrapper\"><div id=\"cf-error-details\"></div></div></body></html>";
+        assert_eq!(blocked_by_markup(page), Some(BlockKind::Challenge));
+        assert_eq!(blocked_by_markup("<title>Just a moment...</title>"), Some(BlockKind::Challenge));
+        // Gallery titles are not challenge pages
+        assert_eq!(blocked_by_markup(ATTENTION_TITLE), None);
+    }
+}
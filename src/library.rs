@@ -0,0 +1,328 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::gallery::{DownloadReport, Gallery, PageSelection};
+use crate::{App, RefreshCli, ctx, http, snapshot};
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+fn read_json<T: Default + for<'de> Deserialize<'de>>(path: &Path) -> Result<T> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(T::default()),
+        Err(e) => return Err(e).with_context(ctx!("Cannot read {path:?}")),
+    };
+    serde_json::from_str(&text)
+        .with_context(ctx!("Invalid state file {path:?}"))
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let json = serde_json::to_vec_pretty(value)
+        .with_context(ctx!("Cannot serialize state file {path:?}"))?;
+    std::fs::write(path, json)
+        .with_context(ctx!("Cannot write state file {path:?}"))
+}
+
+/// Result of the downloads of a gallery so far
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DownloadStatus {
+    /// Every page was downloaded
+    Complete,
+    /// Some pages failed, or were left out with `--pages`
+    Partial,
+    /// Every page failed, or the gallery couldn't be downloaded at all
+    Failed,
+}
+
+/// A page that couldn't be downloaded
+#[derive(Serialize, Deserialize)]
+pub struct PageFailure {
+    pub error: String,
+    pub attempts: u32,
+    pub last_attempt: u64,
+}
+
+/// State of a downloaded gallery, saved as `state.json` in its directory
+#[derive(Serialize, Deserialize, Default)]
+pub struct GalleryState {
+    /// `None` if the gallery was downloaded before the state was recorded
+    #[serde(default)]
+    pub status: Option<DownloadStatus>,
+    /// Failed pages, numbered from 1
+    #[serde(default)]
+    pub failed_pages: BTreeMap<usize, PageFailure>,
+    /// Pages left out with `--pages` and not present, numbered from 1
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub skipped_pages: BTreeSet<usize>,
+    /// Error that stopped the whole download
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Number of downloads of the gallery
+    #[serde(default)]
+    pub attempts: u32,
+    #[serde(default)]
+    pub first_attempt: Option<u64>,
+    #[serde(default)]
+    pub last_attempt: Option<u64>,
+    /// Unix timestamp of when every page was first present
+    #[serde(default)]
+    pub completed: Option<u64>,
+    /// Unix timestamp of when the site answered 404 for the gallery
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub removed_upstream: Option<u64>,
+}
+
+impl GalleryState {
+    fn path(out_path: &Path, id: u32) -> PathBuf {
+        out_path.join(id.to_string()).join("state.json")
+    }
+
+    /// Loads the state of a gallery, the default one if it has no state file
+    pub fn load(out_path: &Path, id: u32) -> Result<Self> {
+        read_json(&Self::path(out_path, id))
+    }
+
+    pub fn save(&self, out_path: &Path, id: u32) -> Result<()> {
+        write_json(&Self::path(out_path, id), self)
+    }
+
+    fn attempt(&mut self) -> u64 {
+        let now = now();
+        self.attempts += 1;
+        self.first_attempt.get_or_insert(now);
+        self.last_attempt = Some(now);
+        now
+    }
+
+    /// Records the result of a download of a gallery with `pages` pages, `skipped` are the pages
+    /// that weren't selected and are still missing
+    pub fn record(&mut self, report: &DownloadReport, pages: usize, skipped: &[usize]) {
+        let now = self.attempt();
+        self.error = None;
+        let mut previous = std::mem::take(&mut self.failed_pages);
+        previous.retain(|page, _| !report.pages.contains(page) || report.failed.iter().any(|(p, _)| p == page));
+        for (page, error) in &report.failed {
+            let attempts = previous.get(page).map_or(0, |f| f.attempts);
+            let failure = PageFailure { error: error.clone(), attempts: attempts + 1, last_attempt: now };
+            previous.insert(*page, failure);
+        }
+        self.failed_pages = previous;
+        // Pages past the end of a gallery uploaded again with less pages
+        self.failed_pages.retain(|&page, _| page <= pages);
+        self.skipped_pages = skipped.iter().copied().collect();
+
+        let status = match self.failed_pages.len() {
+            0 if self.skipped_pages.is_empty() => DownloadStatus::Complete,
+            n if n == pages => DownloadStatus::Failed,
+            _ => DownloadStatus::Partial,
+        };
+        if status == DownloadStatus::Complete {
+            self.completed.get_or_insert(now);
+        }
+        self.status = Some(status);
+    }
+
+    /// Records a download of a gallery that stopped before getting the pages
+    pub fn record_error(&mut self, error: String) {
+        self.attempt();
+        self.error = Some(error);
+        self.status = Some(DownloadStatus::Failed);
+    }
+
+    /// Checks if the last download left something to download again
+    pub fn needs_retry(&self) -> bool {
+        matches!(self.status, Some(DownloadStatus::Partial | DownloadStatus::Failed))
+    }
+}
+
+/// Index of the output directory, saved as `library.json` in it, with what is worth knowing
+/// without reading every gallery
+#[derive(Serialize, Deserialize, Default)]
+pub struct Index {
+    /// Galleries removed from the site, with the timestamp of when it was noticed
+    #[serde(default)]
+    pub removed: BTreeMap<u32, u64>,
+}
+
+impl Index {
+    fn path(out_path: &Path) -> PathBuf {
+        out_path.join("library.json")
+    }
+
+    pub fn load(out_path: &Path) -> Result<Self> {
+        read_json(&Self::path(out_path))
+    }
+
+    pub fn save(&self, out_path: &Path) -> Result<()> {
+        write_json(&Self::path(out_path), self)
+    }
+}
+
+/// Records in the gallery state and in the index that a downloaded gallery is not on the site
+/// anymore, keeping the time it was first noticed
+pub fn mark_removed(out_path: &Path, id: u32) -> Result<()> {
+    let mut state = GalleryState::load(out_path, id)?;
+    let removed = *state.removed_upstream.get_or_insert_with(now);
+    state.save(out_path, id)?;
+
+    let mut index = Index::load(out_path)?;
+    index.removed.insert(id, removed);
+    index.save(out_path)
+}
+
+/// Clears the removed status of a gallery that is on the site again
+pub fn mark_present(out_path: &Path, id: u32) -> Result<()> {
+    let mut state = GalleryState::load(out_path, id)?;
+    if state.removed_upstream.take().is_none() {
+        return Ok(());
+    }
+    state.save(out_path, id)?;
+
+    let mut index = Index::load(out_path)?;
+    index.removed.remove(&id);
+    index.save(out_path)
+}
+
+impl App {
+    pub async fn retry_failed(&self) -> Result<()> {
+        let out_path = self.out_path()?;
+        let mut retries = Vec::new();
+        for gallery in Gallery::read_library(out_path)? {
+            match GalleryState::load(out_path, gallery.id) {
+                Ok(state) if state.needs_retry() => retries.push((gallery, state)),
+                Ok(_) => {}
+                Err(e) => log::warn!("Skipping gallery: {}, invalid state\nError: {e:?}", gallery.id),
+            }
+        }
+        let pages: usize = retries.iter().map(|(_, state)| state.failed_pages.len()).sum();
+        let skipped: usize = retries.iter().map(|(_, state)| state.skipped_pages.len()).sum();
+        log::info!("Found {} galleries to retry, with {pages} failed pages and {skipped} pages left out", retries.len());
+
+        let count = retries.len();
+        for (i, (gallery, state)) in retries.iter().enumerate() {
+            let id = gallery.id;
+            // Galleries that failed as a whole are downloaded again completely, the pages left out
+            // with `--pages` are downloaded with the failed ones
+            let selection = match state.failed_pages.is_empty() && state.skipped_pages.is_empty() {
+                true => None,
+                false => Some(PageSelection::from_pages(
+                    state.failed_pages.keys().chain(&state.skipped_pages).copied(),
+                )),
+            };
+            let (pages, skipped) = (state.failed_pages.len(), state.skipped_pages.len());
+            log::info!(
+                "({}/{count}) Retrying gallery: {id} [{}] failed pages: {pages} left out: {skipped}",
+                i + 1, gallery.title.pretty
+            );
+
+            if self.args.dry_run {
+                continue;
+            }
+            if let Err(e) = self.download_pages(gallery, out_path, true, selection.as_ref()).await {
+                log::warn!("Failed to download gallery: {id}\nError: {e:?}");
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn refresh(&self, refresh: &RefreshCli) -> Result<()> {
+        let out_path = self.out_path()?;
+        let mut galleries = Gallery::read_library(out_path)?;
+        if !refresh.ids.is_empty() {
+            for id in refresh.ids.iter().filter(|id| !galleries.iter().any(|g| g.id == **id)) {
+                log::warn!("Gallery {id} is not in {out_path:?}");
+            }
+            galleries.retain(|g| refresh.ids.contains(&g.id));
+        }
+        // The filters are checked on the new info for galleries saved without the types of their
+        // tags, that are always fetched again
+        let matches = |g: &Gallery| self.args.filters.iter().all(|f| f.check(g).is_ok());
+        galleries.retain(|g| g.has_untyped_tags() || matches(g));
+        let untyped = galleries.iter().filter(|g| g.has_untyped_tags()).count();
+        if untyped > 0 && !self.args.filters.is_empty() {
+            log::warn!("{untyped} galleries were saved without the types of their tags, fetching them to check the filters");
+        }
+
+        let (mut updated, mut unchanged, mut removed, mut failed) = (0, 0, 0, 0);
+        let count = galleries.len();
+        for (i, old) in galleries.iter().enumerate() {
+            let id = old.id;
+            let new = match self.load_gallery(id).await {
+                Ok(g) if old.has_untyped_tags() && !matches(&g) => {
+                    log::debug!("Skipping gallery: {id}, filter not matched");
+                    // Saves the types of the tags anyway, so it's not fetched again the next time
+                    if !self.args.dry_run && !g.pages_changed(old) && let Err(e) = g.replace_info(out_path) {
+                        log::warn!("Failed to save the info of gallery {id}\nError: {e:?}");
+                        self.summary.failed();
+                        failed += 1;
+                    } else {
+                        self.summary.filtered();
+                    }
+                    continue;
+                }
+                Ok(g) => g,
+                Err(e) if http::is_blocked(&e) => return Err(e),
+                Err(e) if http::is_not_found(&e) => {
+                    removed += 1;
+                    continue;
+                }
+                Err(e) => {
+                    log::warn!("Failed to refresh gallery: {id}\nError: {e:?}");
+                    self.summary.failed();
+                    failed += 1;
+                    continue;
+                }
+            };
+
+            let changes = old.changes(&new);
+            if changes.is_empty() {
+                log::info!("({}/{count}) Gallery {id} [{}] is unchanged", i + 1, new.title.pretty);
+                self.summary.present();
+                unchanged += 1;
+                continue;
+            }
+            log::info!("({}/{count}) Gallery {id} [{}] changed", i + 1, new.title.pretty);
+            println!("{id} [{}]", new.title.pretty);
+            for change in changes {
+                println!("  {change}");
+            }
+
+            if new.pages_changed(old) {
+                // Keep the old info, or downloading the gallery wouldn't notice the new pages
+                log::warn!("Gallery {id} was uploaded again with different pages, download it to update it");
+            } else if !self.args.dry_run && let Err(e) = new.replace_info(out_path) {
+                log::warn!("Failed to save the info of gallery {id}\nError: {e:?}");
+                self.summary.failed();
+                failed += 1;
+                continue;
+            }
+            self.summary.downloaded();
+            updated += 1;
+        }
+        log::info!("Refresh: {updated} galleries changed, {unchanged} unchanged, {removed} removed from the site, {failed} failed");
+        Ok(())
+    }
+
+    pub fn list_removed(&self) -> Result<()> {
+        let out_path = self.out_path()?;
+        let index = Index::load(out_path)?;
+        for (&id, &removed) in &index.removed {
+            let info_path = out_path.join(id.to_string()).join("gallery.json");
+            let title = std::fs::read_to_string(&info_path).ok()
+                .and_then(|text| serde_json::from_str::<Gallery>(&text).ok())
+                .map(|g| g.title.pretty)
+                .unwrap_or_default();
+            println!("{id}\tremoved {}\t{title}", snapshot::format_age(removed));
+        }
+        log::info!("{} downloaded galleries were removed from the site", index.removed.len());
+        Ok(())
+    }
+}
@@ -1,17 +1,47 @@
-use std::num::NonZeroU32;
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{ArgMatches, Command, CommandFactory, FromArgMatches};
+use clap::error::ErrorKind as ClapErrorKind;
 
-use reqwest::Client;
+use reqwest::{Client, Proxy, Url};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use reqwest::redirect::Policy as RedirectPolicy;
+use reqwest_cookie_store::CookieStoreMutex;
 
+mod blacklist;
+use blacklist::Blacklist;
+mod catalog;
+use catalog::Catalog;
+mod config;
+use config::Config;
+mod cookies;
+mod export;
+use export::{ListFormat, ListWriter};
+mod filter;
+use filter::Filter;
 mod gallery;
-use gallery::Gallery;
+use gallery::{Gallery, PageSelection, UpdatePolicy};
+mod http;
+use http::ProxySetting;
+mod library;
 mod logging;
 mod query;
-use query::{QueryInfo, QueryResult};
+mod related;
+mod snapshot;
+mod resolver;
+use resolver::{IpPreference, Resolver};
+mod subscription;
+use subscription::Subscriptions;
+mod summary;
+use summary::{Outcome, Summary, format_size};
 
 #[macro_export]
 macro_rules! ctx {
@@ -39,16 +69,175 @@ struct Cli {
     /// will check if all pages are present and try to download the missing ones, this flag
     /// disables this behavior.
     no_check_missing_pages: bool,
+    #[arg(long, verbatim_doc_comment)]
+    #[arg(value_enum, default_value_t)]
+    /// What to do with a downloaded gallery that was uploaded again with different pages
+    ///
+    /// - Detected from a different `media_id`, page count or page types than the saved
+    ///   `gallery.json`, the new version is always downloaded.
+    on_update: UpdatePolicy,
     #[arg(short = 'p', long, verbatim_doc_comment)]
     /// Path to output directory
-    path: PathBuf,
+    ///
+    /// - Required when downloading, can be set in the config file.
+    path: Option<PathBuf>,
+    #[arg(long, verbatim_doc_comment)]
+    /// Show what would be downloaded without writing any file
+    ///
+    /// - Prints each gallery with its missing pages and an estimated size, from the size of a few
+    ///   pages given by HEAD requests, and the totals at the end.
+    /// - Saved state (crawls, subscriptions, catalog, cookie jar) is not updated.
+    dry_run: bool,
+    #[arg(long = "filter", value_name = "EXPR", verbatim_doc_comment)]
+    /// Only download the galleries matching this expression (can be repeated)
+    ///
+    /// - Checked on the gallery info before downloading, skipped galleries are logged.
+    /// - Fields: favorites, pages, id, uploaded (unix timestamp), age (e.g. 30d, 6m, 1y).
+    /// - Comparisons: `<`, `<=`, `>`, `>=`, `=`, `!=`, titles also support `~` for regexes.
+    /// - Tags: tag:name, artist:name, group:, parody:, character:, language:, category:,
+    ///   `*` matches any name, title:text matches part of a title.
+    /// - Combine with `and`, `or`, `not` (or `-`) and parentheses.
+    /// - Example: "favorites >= 500 and pages <= 40 -language:chinese"
+    filters: Vec<Filter>,
+    #[arg(long, value_name = "FILE", verbatim_doc_comment)]
+    /// File with the tags, artists, groups, ... that are never downloaded
+    ///
+    /// - Defaults to `nhentai-downloader/blacklist.toml` in the user config directory
+    ///   (e.g. `~/.config/nhentai-downloader/blacklist.toml`), if it exists.
+    /// - Same format as the `[blacklist]` table of the config file, both are used:
+    ///   tags, artists, groups, parodies, characters, languages and categories lists.
+    /// - Applies to every mode, blacklisted galleries are logged and skipped.
+    blacklist_file: Option<PathBuf>,
+    #[arg(long, verbatim_doc_comment)]
+    /// Ignore the blacklist, for one-off downloads
+    no_blacklist: bool,
+    #[arg(long, env = "NHENTAI_CONFIG", verbatim_doc_comment)]
+    /// Path to the config file
+    ///
+    /// - Defaults to `nhentai-downloader/config.toml` in the user config directory
+    ///   (e.g. `~/.config/nhentai-downloader/config.toml`).
+    config: Option<PathBuf>,
+    #[arg(long, env = "NHENTAI_PROFILE", verbatim_doc_comment)]
+    /// Profile of the config file to use, its options override the top level ones
+    profile: Option<String>,
+    #[command(flatten)]
+    network: NetworkCli,
+}
+
+#[derive(clap::Args)]
+#[command(next_help_heading = "Network options")]
+struct NetworkCli {
+    #[arg(long, verbatim_doc_comment)]
+    /// Load cookies from a Netscape cookies.txt file or a JSON file
+    ///
+    /// - JSON files can contain a list of cookies as exported by browser extensions, or an object
+    ///   mapping cookie names to values that will be sent to nhentai.net.
+    /// - Can be repeated to load multiple files.
+    cookies: Vec<PathBuf>,
+    #[arg(long, verbatim_doc_comment)]
+    /// Cookie jar file, cookies set by the server are saved here and loaded again on the next run
+    cookie_jar: Option<PathBuf>,
+    #[arg(short = 'A', long, verbatim_doc_comment)]
+    /// User-Agent sent with every request
+    ///
+    /// - When passing a `cf_clearance` cookie this must match the browser that obtained it.
+    user_agent: Option<String>,
+    #[arg(short = 'H', long = "header", verbatim_doc_comment)]
+    #[arg(value_name = "NAME: VALUE", value_parser = parse_header)]
+    /// Extra header sent with every request (can be repeated)
+    headers: Vec<(HeaderName, HeaderValue)>,
+    #[arg(long, verbatim_doc_comment)]
+    #[arg(value_name = "URL", value_parser = http::parse_proxy)]
+    /// Proxy used for requests to nhentai.net
+    ///
+    /// - Supported schemes: http, https, socks5 and socks5h (names resolved by the proxy).
+    /// - Also used for the image servers, unless `--cdn-proxy` is given.
+    /// - Use `direct` to ignore the proxy set in the environment.
+    proxy: Option<ProxySetting>,
+    #[arg(long, verbatim_doc_comment)]
+    #[arg(value_name = "URL", value_parser = http::parse_proxy)]
+    /// Proxy used for requests to the image servers (i1.nhentai.net, t1.nhentai.net, ...) and
+    /// the host of `--image-url`
+    ///
+    /// - Use `direct` to connect to the image servers without a proxy.
+    /// - Requests to nhentai.net still use `--proxy`, or the proxy set in the environment.
+    cdn_proxy: Option<ProxySetting>,
+    #[arg(long, verbatim_doc_comment)]
+    #[arg(value_name = "SECONDS")]
+    /// Timeout for establishing connections
+    connect_timeout: Option<u64>,
+    #[arg(long, verbatim_doc_comment)]
+    #[arg(value_name = "SECONDS")]
+    /// Timeout for each read from a connection
+    read_timeout: Option<u64>,
+    #[arg(long, verbatim_doc_comment)]
+    #[arg(value_enum, default_value_t)]
+    /// Address family used when connecting
+    ip_preference: IpPreference,
+    #[arg(long, verbatim_doc_comment)]
+    #[arg(value_name = "HOST=IP", value_parser = resolver::parse_override)]
+    /// Connect to IP instead of resolving HOST (can be repeated)
+    ///
+    /// - Useful to pin the image servers (e.g. `i3.nhentai.net=1.2.3.4`).
+    /// - Repeating the same host gives multiple addresses to try.
+    resolve: Vec<(String, IpAddr)>,
+    #[arg(long, verbatim_doc_comment)]
+    #[arg(value_name = "URL", default_value = "https://nhentai.net/")]
+    /// Base url of the site, for mirrors or a local server serving saved pages
+    site_url: Url,
+    #[arg(long, verbatim_doc_comment)]
+    #[arg(value_name = "URL")]
+    /// Base url of the image servers, instead of a random one of i1.nhentai.net to i4.nhentai.net
+    ///
+    /// - Pages are fetched from `<URL>/galleries/<media_id>/<page>.<ext>`.
+    image_url: Option<Url>,
+}
+
+fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue)> {
+    let (name, value) = header.split_once(':')
+        .with_context(ctx!("Header must be in the `Name: value` format"))?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes())
+        .with_context(ctx!("Invalid header name `{}`", name.trim()))?;
+    let value = HeaderValue::from_str(value.trim())
+        .with_context(ctx!("Invalid value for header `{name}`"))?;
+    Ok((name, value))
 }
 
 #[derive(clap::Subcommand)]
 #[command(disable_help_subcommand = true, flatten_help = true)]
 enum ActionType {
     Single(SingleCli),
+    Batch(BatchCli),
     Query(QueryCli),
+    Favorites(FavoritesCli),
+    Listing(ListingCli),
+    Related(RelatedCli),
+    Config(ConfigCli),
+    Blacklist(BlacklistCli),
+    Sub(SubCli),
+    Sync(SyncCli),
+    Catalog(CatalogCli),
+    Search(SearchCli),
+    Refresh(RefreshCli),
+    Library(LibraryCli),
+    RetryFailed(RetryFailedCli),
+}
+
+impl ActionType {
+    /// Checks if the action needs the output directory
+    fn writes_output(&self) -> bool {
+        match self {
+            Self::Single(_) | Self::Refresh(_) | Self::Library(_) | Self::RetryFailed(_) => true,
+            Self::Batch(b) => !b.list.list,
+            Self::Query(q) => q.action.is_none() && !q.list.list,
+            Self::Favorites(f) => !f.list.list,
+            Self::Listing(l) => !l.list.list,
+            Self::Related(r) => !r.graph,
+            // Subscriptions can have their own output directory, it's checked for each one
+            Self::Config(_) | Self::Blacklist(_) | Self::Sub(_) | Self::Sync(_) => false,
+            Self::Catalog(_) | Self::Search(_) => false,
+        }
+    }
 }
 
 #[derive(clap::Args)]
@@ -58,24 +247,243 @@ struct SingleCli {
     #[arg(verbatim_doc_comment)]
     /// Id of the gallery to download
     id: u32,
+    #[arg(long, value_name = "PAGES", verbatim_doc_comment)]
+    /// Only download these pages, numbered from 1 (e.g. `1-5,12,20-`)
+    ///
+    /// - Only the selected pages are checked for missing ones.
+    pages: Option<PageSelection>,
 }
 
 #[derive(clap::Args)]
 #[command(disable_help_flag = true)]
+/// Id list download mode, e.g. for the lists printed by `--list`
+struct BatchCli {
+    #[arg(verbatim_doc_comment)]
+    /// File with one gallery per line, as an id or an url, `-` reads from stdin
+    ///
+    /// - Blank lines and lines starting with `#` are ignored, for csv lists the first column is used
+    ///   and for jsonl lists the `id` field.
+    file: PathBuf,
+    #[command(flatten)]
+    list: ListCli,
+}
+
+#[derive(clap::Args)]
+#[command(disable_help_flag = true)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 /// Query download mode
+///
+/// The result list of every run is saved as a snapshot, see `query diff`.
 struct QueryCli {
-    #[arg(verbatim_doc_comment)]
-    /// Query string to fetch galleries
+    #[command(subcommand)]
+    action: Option<QueryAction>,
+    #[arg(required_unless_present = "queries_file", verbatim_doc_comment)]
+    #[allow(clippy::doc_overindented_list_items)]
+    /// Query strings to fetch galleries, run one after the other
     ///
     /// - By default this will download all the galleries of first page of the query.
     /// - If the query refers to a single gallery (e.g. "#12345") only that gallery will be
     ///     downloaded, other flags will be ignored.
     /// - You can find the query syntax here: https://nhentai.net/info/
-    query: String, // TODO: verbatim_doc_comment
+    /// - Galleries already handled by a previous query of the run are skipped.
+    query: Vec<String>, // TODO: verbatim_doc_comment
+    #[arg(long, value_name = "FILE", verbatim_doc_comment)]
+    /// File with more queries, one per line, run after the ones on the command line
+    ///
+    /// - Each line is a query followed by its own options, with shell quoting
+    ///   (e.g. `"tag:full color" --sort popular --count 2`).
+    /// - Allowed options: --sort, --first-page, --last-page, --count, the missing ones take the
+    ///   values of the command line.
+    /// - Blank lines and lines starting with `#` are ignored.
+    queries_file: Option<PathBuf>,
+    #[arg(long, verbatim_doc_comment)]
+    #[arg(conflicts_with_all = ["first_page", "last_page", "count", "reverse", "until_known"])]
+    /// Split each query in consecutive `uploaded:` windows, to get past the result limits of the
+    /// search
+    ///
+    /// - Windows go from the newest galleries to the oldest ones, all their pages are downloaded.
+    /// - The size of each window is chosen from the page count of the previous one, so that it
+    ///   stays below `--split-pages`.
+    split_dates: bool,
+    #[arg(long, verbatim_doc_comment)]
+    #[arg(value_name = "PAGES", default_value = "50")]
+    /// Maximum number of result pages of a window of `--split-dates`
+    split_pages: NonZeroU32,
     #[arg(short = 's', long, verbatim_doc_comment)]
     #[arg(value_enum, default_value_t)]
     /// Query sort order
     sort: SortType,
+    #[command(flatten)]
+    range: PageRangeCli,
+    #[command(flatten)]
+    list: ListCli,
+}
+
+/// A line of the `--queries-file`
+#[derive(clap::Parser)]
+#[command(name = "query", no_binary_name = true, disable_help_flag = true)]
+struct QueryLineCli {
+    query: String,
+    #[arg(short = 's', long, value_enum)]
+    sort: Option<SortType>,
+    #[arg(short = 'f', long)]
+    first_page: Option<NonZeroU32>,
+    #[arg(short = 'l', long, conflicts_with = "count")]
+    last_page: Option<NonZeroU32>,
+    #[arg(short = 'n', long)]
+    count: Option<u32>,
+}
+
+impl QueryCli {
+    /// Queries to run with their sort order and page range, from the command line and the file
+    fn queries(&self) -> Result<Vec<(String, SortType, PageRangeCli)>> {
+        let mut queries: Vec<_> = self.query.iter()
+            .map(|q| (q.clone(), self.sort, self.range.clone()))
+            .collect();
+
+        let Some(ref path) = self.queries_file else {
+            return Ok(queries);
+        };
+        let text = std::fs::read_to_string(path)
+            .with_context(ctx!("Cannot read queries file {path:?}"))?;
+        for (n, line) in text.lines().enumerate().map(|(n, l)| (n + 1, l.trim())) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words = shlex::split(line)
+                .with_context(ctx!("Invalid quoting at line {n} of {path:?}"))?;
+            let parsed = <QueryLineCli as clap::Parser>::try_parse_from(words)
+                .with_context(ctx!("Invalid query at line {n} of {path:?}"))?;
+
+            let range = match (parsed.first_page, parsed.last_page, parsed.count) {
+                (None, None, None) => self.range.clone(),
+                (first_page, last_page, count) => PageRangeCli {
+                    first_page: first_page.unwrap_or(NonZeroU32::MIN),
+                    last_page,
+                    count,
+                    ..self.range.clone()
+                },
+            };
+            queries.push((parsed.query, parsed.sort.unwrap_or(self.sort), range));
+        }
+        Ok(queries)
+    }
+}
+
+#[derive(clap::Subcommand)]
+enum QueryAction {
+    /// Compare two snapshots of a query: added, removed and moved galleries
+    Diff(QueryDiffCli),
+}
+
+#[derive(clap::Args)]
+struct QueryDiffCli {
+    #[arg(verbatim_doc_comment)]
+    /// Query string, as given when downloading
+    query: String,
+    #[arg(short = 's', long, verbatim_doc_comment)]
+    #[arg(value_enum, default_value_t)]
+    /// Query sort order, as given when downloading
+    sort: SortType,
+    #[arg(long, value_name = "FILE", verbatim_doc_comment)]
+    /// Older snapshot, defaults to the second to last one of the query
+    old: Option<PathBuf>,
+    #[arg(long, value_name = "FILE", verbatim_doc_comment)]
+    /// Newer snapshot, defaults to the last one of the query
+    new: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+#[command(disable_help_flag = true)]
+/// Favorites download mode, requires the session cookie of a logged in user
+struct FavoritesCli {
+    #[arg(short = 'q', long, verbatim_doc_comment)]
+    /// Only include the favorites matching this text (same as the favorites search box)
+    query: Option<String>,
+    #[command(flatten)]
+    range: PageRangeCli,
+    #[command(flatten)]
+    list: ListCli,
+}
+
+#[derive(clap::Args)]
+#[command(disable_help_flag = true)]
+/// Listing page download mode (tag, artist, group, parody, character, language, category)
+struct ListingCli {
+    #[arg(verbatim_doc_comment)]
+    /// Listing to download, as an url or a `type:name` pair
+    ///
+    /// - Urls can include the sort order (e.g. "https://nhentai.net/tag/full-color/popular-week").
+    /// - Pairs use the name shown on the site (e.g. "artist:shindol", "tag:full color").
+    listing: String,
+    #[arg(short = 's', long, verbatim_doc_comment)]
+    #[arg(value_enum, default_value_t)]
+    /// Listing sort order, ignored if the url selects one
+    sort: SortType,
+    #[command(flatten)]
+    range: PageRangeCli,
+    #[command(flatten)]
+    list: ListCli,
+}
+
+#[derive(clap::Args)]
+#[command(disable_help_flag = true)]
+/// Related galleries crawl mode, follows the "More Like This" galleries breadth-first
+struct RelatedCli {
+    #[arg(required = true, verbatim_doc_comment)]
+    /// Ids of the galleries to start from
+    ids: Vec<u32>,
+    #[arg(short = 'd', long, verbatim_doc_comment)]
+    #[arg(default_value = "1")]
+    /// How many links to follow from the starting galleries
+    ///
+    /// - 0 only downloads the starting galleries.
+    depth: u32,
+    #[arg(short = 'm', long, verbatim_doc_comment)]
+    #[arg(default_value = "100")]
+    /// Maximum number of galleries to visit, including the starting ones
+    limit: usize,
+    #[arg(long, verbatim_doc_comment)]
+    /// Print the graph of the related galleries (graphviz dot format) instead of downloading them
+    graph: bool,
+    #[arg(long, verbatim_doc_comment)]
+    /// File where the state of the crawl is saved, to continue it if interrupted
+    ///
+    /// - Defaults to a file named after the starting ids in the user data directory
+    ///   (e.g. `~/.local/share/nhentai-downloader/related/12345.json`).
+    state: Option<PathBuf>,
+    #[arg(long, verbatim_doc_comment)]
+    /// Start the crawl again, ignoring the saved state
+    restart: bool,
+}
+
+#[derive(clap::Args)]
+struct ListCli {
+    #[arg(long, verbatim_doc_comment)]
+    /// Print the list of the galleries instead of downloading them
+    list: bool,
+    #[arg(long, verbatim_doc_comment)]
+    #[arg(value_enum, default_value_t)]
+    /// Format of the list
+    list_format: ListFormat,
+    #[arg(long, value_name = "FILE", verbatim_doc_comment)]
+    /// Write the list to this file instead of stdout
+    list_file: Option<PathBuf>,
+    #[arg(long, verbatim_doc_comment)]
+    /// Load the info of every gallery of the list, to include titles, tags, pages, ...
+    ///
+    /// - The blacklist and the filters are only applied to the list when this is set.
+    enrich: bool,
+}
+
+impl ListCli {
+    fn writer(&self) -> Result<ListWriter> {
+        ListWriter::new(self.list_format, self.list_file.as_deref(), self.enrich)
+    }
+}
+
+#[derive(clap::Args, Clone)]
+struct PageRangeCli {
     #[arg(short = 'f', long, verbatim_doc_comment)]
     #[arg(default_value = "1")]
     /// First page to download (inclusive)
@@ -95,10 +503,310 @@ struct QueryCli {
     /// - Set to 0 to download all pages.
     /// - If this number is bigger than the available pages all pages will be downloaded.
     count: Option<u32>,
+    #[arg(short = 'k', long, verbatim_doc_comment)]
+    /// Stop paging once galleries already in the output directory are found
+    ///
+    /// - Meant for keeping a query sorted by recent up to date, all pages are visited until then.
+    /// - Known galleries are skipped without checking for missing pages.
+    until_known: bool,
+    #[arg(long, verbatim_doc_comment)]
+    #[arg(value_name = "COUNT", default_value = "1")]
+    /// Number of consecutive known galleries that stop `--until-known`
+    known_run: NonZeroUsize,
+    #[arg(short = 'r', long, verbatim_doc_comment)]
+    #[arg(conflicts_with = "until_known")]
+    /// Visit the pages from the last one backward, and the galleries of each page from the bottom
+    ///
+    /// - With the recent sort order this downloads the galleries in chronological order.
+    /// - Without a page range all pages are visited.
+    reverse: bool,
+    #[arg(short = 'm', long, verbatim_doc_comment)]
+    #[arg(value_name = "COUNT")]
+    /// Stop after this many galleries have been downloaded or checked, whatever page they are on
+    ///
+    /// - Galleries skipped as duplicates, by `--until-known`, by the blacklist or by the filters
+    ///   are not counted.
+    max_galleries: Option<NonZeroUsize>,
+}
+
+impl PageRangeCli {
+    /// Last page to download, `all` selects whether to download all pages when no limit is given
+    fn last_page(&self, pages: NonZeroU32, all: bool) -> NonZeroU32 {
+        let last_page = match (self.last_page, self.count) {
+            (Some(last), _) => last,
+            (_, Some(0)) => pages,
+            (_, Some(count)) => self.first_page.checked_add(count - 1).unwrap_or(pages),
+            (None, None) if all => pages,
+            (None, None) => self.first_page,
+        };
+        last_page.min(pages)
+    }
+}
+
+#[derive(clap::Args)]
+#[command(disable_help_flag = true)]
+/// Config file management
+struct ConfigCli {
+    #[command(subcommand)]
+    action: ConfigAction,
+}
+
+#[derive(clap::Subcommand)]
+enum ConfigAction {
+    /// Print the effective value of every option and where it comes from
+    Show,
+}
+
+#[derive(clap::Args)]
+#[command(disable_help_flag = true)]
+/// Blacklist management
+struct BlacklistCli {
+    #[command(subcommand)]
+    action: BlacklistAction,
+}
+
+#[derive(clap::Subcommand)]
+enum BlacklistAction {
+    /// Explain why a gallery would be excluded by the blacklist
+    Test {
+        /// Id of the gallery to check
+        id: u32,
+    },
+}
+
+#[derive(clap::Args)]
+#[command(disable_help_flag = true)]
+/// Saved subscriptions management, see `sync`
+struct SubCli {
+    #[command(subcommand)]
+    action: SubAction,
+    #[command(flatten)]
+    state: SubStateCli,
+}
+
+#[derive(clap::Subcommand)]
+enum SubAction {
+    /// Save a query as a subscription
+    Add {
+        /// Name of the subscription
+        name: String,
+        /// Query string, same syntax as `query`
+        query: String,
+        #[arg(short = 's', long, verbatim_doc_comment)]
+        #[arg(value_enum, default_value_t)]
+        /// Query sort order
+        ///
+        /// - With `recent` each sync visits the pages until it finds the galleries of the previous
+        ///   one, with the other orders the first `--count` pages are checked for galleries that
+        ///   were not seen by the previous syncs.
+        sort: SortType,
+        #[arg(short = 'n', long, verbatim_doc_comment)]
+        /// Number of pages checked by each sync, not with the `recent` sort
+        ///
+        /// - By default only the first page is checked.
+        /// - A sync stops early at a page whose galleries were all seen by the previous syncs.
+        count: Option<NonZeroU32>,
+        #[arg(long = "filter", value_name = "EXPR", verbatim_doc_comment)]
+        /// Only download the galleries matching this expression (can be repeated), see `--filter`
+        filters: Vec<Filter>,
+        #[arg(short = 'o', long, verbatim_doc_comment)]
+        /// Output directory of this subscription, instead of `--path`
+        output: Option<PathBuf>,
+        #[arg(long, value_name = "NAME", verbatim_doc_comment)]
+        /// Profile of the config file used to sync this subscription
+        ///
+        /// - Its options (output directory, filters, network, ...) replace the ones of the profile
+        ///   selected with `--profile`, the command line options still take precedence.
+        output_profile: Option<String>,
+        #[arg(long, value_name = "ID", verbatim_doc_comment)]
+        /// Only download galleries newer than this one, only with the `recent` sort
+        ///
+        /// - By default the first sync downloads the first page of the query.
+        last_seen: Option<u32>,
+    },
+    /// List the subscriptions
+    List,
+    /// Remove a subscription
+    Rm {
+        /// Name of the subscription
+        name: String,
+    },
+}
+
+#[derive(clap::Args)]
+#[command(disable_help_flag = true)]
+/// Fetch again the info of the downloaded galleries, without touching their pages
+struct RefreshCli {
+    #[arg(verbatim_doc_comment)]
+    /// Ids of the galleries to refresh, all of them if none is given
+    ///
+    /// - `--filter` is matched against the saved info to select a subset.
+    ids: Vec<u32>,
+}
+
+#[derive(clap::Args)]
+#[command(disable_help_flag = true)]
+/// Download again the pages that failed in the galleries of the output directory
+///
+/// Uses the failures recorded in the `state.json` of each gallery.
+struct RetryFailedCli {}
+
+#[derive(clap::Args)]
+#[command(disable_help_flag = true)]
+/// Information about the downloaded galleries
+struct LibraryCli {
+    #[command(subcommand)]
+    action: LibraryAction,
+}
+
+#[derive(clap::Subcommand)]
+enum LibraryAction {
+    /// List the downloaded galleries that were removed from the site
+    Removed,
+}
+
+#[derive(clap::Args)]
+#[command(disable_help_flag = true)]
+/// Download the new galleries of the subscriptions since their last sync
+struct SyncCli {
+    #[arg(verbatim_doc_comment)]
+    /// Names of the subscriptions to sync, all of them if none is given
+    names: Vec<String>,
+    #[command(flatten)]
+    state: SubStateCli,
+}
+
+#[derive(clap::Args)]
+struct SubStateCli {
+    #[arg(long, global = true, verbatim_doc_comment)]
+    /// File where the subscriptions are saved
+    ///
+    /// - Defaults to `subscriptions.json` in the user data directory
+    ///   (e.g. `~/.local/share/nhentai-downloader/subscriptions.json`).
+    subscriptions: Option<PathBuf>,
+}
+
+impl SubStateCli {
+    fn load(&self) -> Result<Subscriptions> {
+        let path = match self.subscriptions {
+            Some(ref path) => path.clone(),
+            None => Subscriptions::default_path()
+                .with_context(ctx!("Cannot find the user data directory, pass `--subscriptions`"))?,
+        };
+        Subscriptions::load(&path)
+    }
+}
+
+#[derive(clap::Args)]
+#[command(disable_help_flag = true)]
+/// Local catalog of gallery info, fetched without the images
+struct CatalogCli {
+    #[command(subcommand)]
+    action: CatalogAction,
+    #[command(flatten)]
+    db: CatalogDbCli,
+}
+
+#[derive(clap::Subcommand)]
+enum CatalogAction {
+    /// Fetch the info of the galleries of id ranges
+    Ids {
+        #[arg(required = true, value_name = "RANGE", value_parser = parse_id_range)]
+        /// Ids or ranges of ids (e.g. `12345`, `1000-2000`)
+        ranges: Vec<RangeInclusive<u32>>,
+        #[command(flatten)]
+        refresh: CatalogRefreshCli,
+    },
+    /// Fetch the info of the galleries of the results of a query
+    Query {
+        /// Query string, same syntax as `query`
+        query: String,
+        #[arg(short = 's', long, value_enum, default_value_t)]
+        /// Query sort order
+        sort: SortType,
+        #[arg(short = 'f', long, default_value = "1")]
+        /// First result page
+        first_page: NonZeroU32,
+        #[arg(short = 'n', long, default_value = "0")]
+        /// Number of result pages, 0 for all of them
+        count: u32,
+        #[command(flatten)]
+        refresh: CatalogRefreshCli,
+    },
+    /// Print the number of entries in the catalog
+    Stats,
+}
+
+#[derive(clap::Args)]
+struct CatalogRefreshCli {
+    #[arg(long, value_name = "AGE", value_parser = filter::parse_duration, verbatim_doc_comment)]
+    /// Fetch again the galleries fetched longer than this ago (e.g. 30d, 6m)
+    ///
+    /// - By default the galleries already in the catalog are skipped, so running the same command
+    ///   again resumes an interrupted crawl.
+    refresh: Option<u64>,
+}
+
+#[derive(clap::Args)]
+struct CatalogDbCli {
+    #[arg(long, global = true, value_name = "FILE", verbatim_doc_comment)]
+    /// Catalog database
+    ///
+    /// - Defaults to `catalog.sqlite` in the user data directory
+    ///   (e.g. `~/.local/share/nhentai-downloader/catalog.sqlite`).
+    db: Option<PathBuf>,
+}
+
+impl CatalogDbCli {
+    fn path(&self) -> Result<PathBuf> {
+        match self.db {
+            Some(ref path) => Ok(path.clone()),
+            None => Catalog::default_path()
+                .with_context(ctx!("Cannot find the user data directory, pass `--db`")),
+        }
+    }
+}
+
+fn parse_id_range(text: &str) -> Result<RangeInclusive<u32>> {
+    let (start, end) = text.split_once('-').unwrap_or((text, text));
+    let start = start.trim().parse()
+        .with_context(ctx!("Invalid id `{start}`"))?;
+    let end = end.trim().parse()
+        .with_context(ctx!("Invalid id `{end}`"))?;
+    if start > end {
+        anyhow::bail!("Range start must not be bigger than its end in `{text}`");
+    }
+    Ok(start..=end)
+}
+
+#[derive(clap::Args)]
+#[command(disable_help_flag = true)]
+/// Search the catalog and the downloaded galleries with a filter expression
+struct SearchCli {
+    #[arg(verbatim_doc_comment)]
+    /// Filter expression, same syntax as `--filter` (e.g. "artist:shindol pages > 20")
+    expr: Filter,
+    #[arg(long, verbatim_doc_comment)]
+    #[arg(conflicts_with = "library_only")]
+    /// Only search the catalog
+    catalog_only: bool,
+    #[arg(long, verbatim_doc_comment)]
+    /// Only search the galleries downloaded to `--path`
+    library_only: bool,
+    #[arg(long, verbatim_doc_comment)]
+    #[arg(value_enum, default_value_t)]
+    /// Format of the results
+    list_format: ListFormat,
+    #[arg(long, value_name = "FILE", verbatim_doc_comment)]
+    /// Write the results to this file instead of stdout
+    list_file: Option<PathBuf>,
+    #[command(flatten)]
+    db: CatalogDbCli,
 }
 
 /// Possible sort orders for a query
-#[derive(clap::ValueEnum, Clone, Copy, Default)]
+#[derive(clap::ValueEnum, serde::Serialize, serde::Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
 enum SortType {
     #[default]
     Recent,
@@ -110,11 +818,71 @@ enum SortType {
 struct App {
     args: Cli,
     client: Client,
+    cookies: Arc<CookieStoreMutex>,
+    blacklist: Blacklist,
+    /// Galleries handled by the listings of this run, to skip them when they show up again
+    seen: Mutex<HashSet<u32>>,
+    summary: Arc<Summary>,
 }
 
 impl App {
-    fn new(args: Cli) -> Result<Self> {
-        let client = Client::builder()
+    fn new(args: Cli, blacklist: Blacklist) -> Result<Self> {
+        let network = &args.network;
+
+        let mut cookies = match network.cookie_jar {
+            Some(ref path) => cookies::load_jar(path)?,
+            None => Default::default(),
+        };
+        for path in &network.cookies {
+            let count = cookies::import(&mut cookies, path)?;
+            log::debug!("Loaded {count} cookies from {path:?}");
+        }
+        let cookies = Arc::new(CookieStoreMutex::new(cookies));
+
+        let mut headers = HeaderMap::new();
+        for (name, value) in &network.headers {
+            headers.append(name, value.clone());
+        }
+
+        let mut builder = Client::builder()
+            .cookie_provider(Arc::clone(&cookies))
+            .default_headers(headers);
+        if let Some(ref user_agent) = network.user_agent {
+            builder = builder.user_agent(user_agent);
+        }
+
+        if network.proxy.is_some() || network.cdn_proxy.is_some() {
+            // Configuring any proxy disables the ones of the environment in reqwest, so they are
+            // looked up here for the hosts without a proxy option
+            let site = network.proxy.clone();
+            let cdn = network.cdn_proxy.clone().or_else(|| site.clone());
+            let image_host = network.image_url.as_ref().and_then(|url| url.host_str()).map(str::to_string);
+            builder = builder.proxy(Proxy::custom(move |url| {
+                let setting = match url.host_str() {
+                    Some(host) if http::is_cdn_host(host) || image_host.as_deref() == Some(host) => &cdn,
+                    _ => &site,
+                };
+                match setting {
+                    Some(ProxySetting::Url(url)) => Some(url.clone()),
+                    Some(ProxySetting::Direct) => None,
+                    None => http::env_proxy(url),
+                }
+            }));
+        }
+
+        if let Some(secs) = network.connect_timeout {
+            builder = builder.connect_timeout(Duration::from_secs(secs));
+        }
+        if let Some(secs) = network.read_timeout {
+            builder = builder.read_timeout(Duration::from_secs(secs));
+        }
+
+        if !network.resolve.is_empty() || network.ip_preference != IpPreference::Auto {
+            let resolver = Resolver::new(&network.resolve, network.ip_preference);
+            builder = builder.dns_resolver(Arc::new(resolver));
+        }
+
+        let client = builder
             .redirect(RedirectPolicy::custom(|attempt| {
                 // HACK: because there is no way to set the redirect policy of a client after building
                 // it, we use this function to ignore redirects when redirecting away from the search
@@ -131,105 +899,331 @@ impl App {
             .build()
             .with_context(ctx!("Cannot build http client"))?;
 
-        Ok(Self { args, client })
+        Ok(Self { args, client, cookies, blacklist, seen: Default::default(), summary: Default::default() })
     }
 
-    async fn run(&self) -> Result<()> {
-        match self.args.action {
-            ActionType::Query(ref q) => self.download_query(q).await,
-            ActionType::Single(SingleCli { id }) => self.download_gallery(id, None).await,
-        }?;
+    fn out_path(&self) -> Result<&Path> {
+        self.args.path.as_deref()
+            .with_context(ctx!("Missing output directory, pass `--path` or set `path` in the config file"))
+    }
 
+    /// Checks the arguments that can be checked before starting, fails early instead of after
+    /// loading the first gallery
+    fn check_args(&self) -> Result<()> {
+        if self.args.action.writes_output() {
+            self.out_path()?;
+        }
         Ok(())
     }
 
-    async fn download_gallery(&self, id: u32, progress: Option<(usize, usize)>) -> Result<()> {
-        let gallery = Gallery::load(&self.client, id).await
-            .with_context(ctx!("Failed to load gallery {id}"))?;
+    async fn run(&self) -> Result<()> {
+        let res = match self.args.action {
+            ActionType::Query(QueryCli { action: Some(QueryAction::Diff(ref d)), .. }) => Self::diff_query(d),
+            ActionType::Query(ref q) => self.download_query(q).await,
+            ActionType::Favorites(ref f) => self.download_favorites(f).await,
+            ActionType::Listing(ref l) => self.download_tagged(l).await,
+            ActionType::Related(ref r) => self.crawl_related(r).await,
+            ActionType::Single(SingleCli { id, .. }) => self.download_gallery(id, None).await,
+            ActionType::Batch(ref b) => self.download_batch(b).await,
+            ActionType::Blacklist(BlacklistCli { action: BlacklistAction::Test { id } }) => self.test_blacklist(id).await,
+            ActionType::Sub(ref s) => self.manage_subscriptions(s),
+            ActionType::Sync(ref s) => self.sync(s).await,
+            ActionType::Catalog(ref c) => self.update_catalog(c).await,
+            ActionType::Search(ref s) => self.search(s),
+            ActionType::Refresh(ref r) => self.refresh(r).await,
+            ActionType::Library(LibraryCli { action: LibraryAction::Removed }) => self.list_removed(),
+            ActionType::RetryFailed(_) => self.retry_failed().await,
+            ActionType::Config(_) => unreachable!("config commands are handled before creating the app"),
+        };
 
-        match progress {
-            Some((pos, end)) => log::info!("({pos}/{end}) id: {id} [{}] pages: {}", gallery.title.pretty, gallery.pages()),
-            None => log::info!("Downloading gallery: {id} [{}] pages: {}", gallery.title.pretty, gallery.pages()),
+        self.summary.log();
+        self.save_cookie_jar();
+        res
+    }
+
+    fn save_cookie_jar(&self) {
+        if let Some(ref path) = self.args.network.cookie_jar && !self.args.dry_run {
+            let store = self.cookies.lock().unwrap();
+            if let Err(e) = cookies::save_jar(&store, path) {
+                log::warn!("Failed to save cookie jar\nError: {e:?}");
+            }
         }
+    }
+
+    /// App with the options of the command line read again with a profile of the config file, that
+    /// adds its counts to the summary of this one
+    fn with_profile(&self, profile: &str) -> Result<Self> {
+        let config = Config::load(self.args.config.as_deref(), Some(profile))?;
+        let (_, matches) = parse_with_config(&config, std::env::args_os())
+            .with_context(ctx!("Cannot apply profile `{profile}`"))?;
+        let args = Cli::from_arg_matches(&matches)?;
+        let blacklist = Blacklist::load(&config, args.blacklist_file.as_deref())?;
 
-        gallery.download(&self.client, &self.args.path, self.args.overwrite, !self.args.no_check_missing_pages).await
-            .with_context(ctx!("Failed to download gallery {id}"))
+        let mut app = Self::new(args, blacklist)?;
+        app.summary = Arc::clone(&self.summary);
+        Ok(app)
     }
 
-    async fn download_query(&self, query: &QueryCli) -> Result<()> {
-        let query_res = QueryInfo::load(&self.client, &query.query, query.sort, query.first_page).await
-            .with_context(ctx!("Failed to load query `{}`", query.query))?;
+    /// Loads the info of a gallery, keeping track of the downloaded galleries removed from the site
+    async fn load_gallery(&self, id: u32) -> Result<Gallery> {
+        let res = Gallery::load(&self.client, &self.args.network.site_url, id).await
+            .with_context(ctx!("Failed to load gallery {id}"));
 
-        let (query_info, galleries) = match query_res {
-            QueryResult::Gallery(id) => {
-                log::info!("The provided query points to a single gallery");
-                return self.download_gallery(id, None).await;
+        let Some(ref out_path) = self.args.path else { return res };
+        if self.args.dry_run || !Gallery::is_downloaded(out_path, id).await {
+            return res;
+        }
+        let status = match res {
+            Err(ref e) if http::is_not_found(e) => {
+                log::warn!("Gallery {id} was removed from the site, the downloaded copy is the only one left");
+                library::mark_removed(out_path, id)
             }
-            QueryResult::QueryList(i, g) => (i, g)
+            Err(_) => Ok(()),
+            Ok(_) => library::mark_present(out_path, id),
         };
-
-        if query_info.pages() < query.first_page {
-            anyhow::bail!("The first page must be less that the number of pages (it's {})", query_info.pages());
+        if let Err(e) = status {
+            log::warn!("Failed to update the state of gallery: {id}\nError: {e:?}");
         }
+        res
+    }
 
-        log::info!("Found {} pages available for query `{}`", query_info.pages(), query.query);
+    async fn download_gallery(&self, id: u32, progress: Option<(usize, usize)>) -> Result<()> {
+        let gallery = self.load_gallery(id).await
+            .inspect_err(|_| self.summary.failed())?;
+        self.download_loaded_gallery(&gallery, progress).await
+    }
 
-        let last_page = match (query.last_page, query.count) {
-            (Some(last), _) => last,
-            (_, Some(0)) => query_info.pages(),
-            (_, Some(count)) => query.first_page.checked_add(count - 1).unwrap_or(query_info.pages()),
-            (None, None) => unreachable!()
-        };
-        let last_page = last_page.min(query_info.pages());
+    async fn download_loaded_gallery(&self, gallery: &Gallery, progress: Option<(usize, usize)>) -> Result<()> {
+        self.download_loaded_gallery_to(gallery, self.out_path()?, &[], progress).await
+    }
+
+    /// Checks the blacklist and the filters of the command line and `extra_filters`, skipped galleries
+    /// are logged and counted
+    fn is_excluded(&self, gallery: &Gallery, extra_filters: &[Filter]) -> bool {
+        let id = gallery.id;
+
+        if !self.args.no_blacklist {
+            let matches = self.blacklist.check(gallery);
+            if !matches.is_empty() {
+                log::info!("Skipping gallery: {id} [{}], blacklisted: {}", gallery.title.pretty, matches.join(", "));
+                self.summary.blacklisted();
+                return true;
+            }
+        }
 
-        log::info!(">>> ({}/{last_page}) Downloading query page #{}", query.first_page, query.first_page);
-        let gallery_count = galleries.len();
-        for (i, gallery) in galleries.into_iter().enumerate() {
-            if let Err(e) = self.download_gallery(gallery, Some((i + 1, gallery_count))).await {
-                log::warn!("Failed to download gallery: {gallery}\nError: {e:?}");
+        for filter in self.args.filters.iter().chain(extra_filters) {
+            if let Err(reason) = filter.check(gallery) {
+                log::info!("Skipping gallery: {id} [{}], filter not matched: {reason}", gallery.title.pretty);
+                self.summary.filtered();
+                return true;
             }
         }
+        false
+    }
 
-        for page in (query.first_page.get()..=last_page.get()).skip(1) {
-            // SAFETY: None of the numbers between two non-zero numbers are zero.
-            let page = unsafe { NonZeroU32::new_unchecked(page) };
+    /// Downloads a gallery to `out_path`, if it's not blacklisted and it matches the filters of the
+    /// command line and `extra_filters`
+    async fn download_loaded_gallery_to(
+        &self,
+        gallery: &Gallery,
+        out_path: &Path,
+        extra_filters: &[Filter],
+        progress: Option<(usize, usize)>,
+    ) -> Result<()> {
+        let id = gallery.id;
 
-            let galleries = match query_info.load_page(&self.client, page).await {
-                Ok(g) => g,
-                Err(e) => {
-                    log::warn!("Failed to download query page: {page}\nError: {e:?}");
-                    continue;
-                }
-            };
+        if self.is_excluded(gallery, extra_filters) {
+            return Ok(());
+        }
 
-            log::info!(">>> ({page}/{last_page}) Downloading query page #{page}");
-            let gallery_count = galleries.len();
-            for (i, gallery) in galleries.into_iter().enumerate() {
-                if let Err(e) = self.download_gallery(gallery, Some((i + 1, gallery_count))).await {
-                    log::warn!("Failed to download gallery: {gallery}\nError: {e:?}");
-                }
+        let selection = self.page_selection();
+        if let Some(selection) = selection {
+            selection.check(gallery.pages())
+                .with_context(ctx!("Invalid page selection for gallery {id}"))
+                .inspect_err(|_| self.summary.failed())?;
+        }
+
+        // The pages of a gallery uploaded again are not just missing, all of them are downloaded
+        let old = Gallery::read_info(out_path, id).unwrap_or_else(|e| {
+            log::warn!("Cannot compare gallery {id} with the downloaded one\nError: {e:?}");
+            None
+        });
+        let old = old.filter(|old| gallery.pages_changed(old));
+
+        if self.args.dry_run {
+            self.plan_gallery(gallery, out_path, old.is_some()).await;
+            return Ok(());
+        }
+
+        if let Some(ref old) = old {
+            log::info!(
+                "Gallery {id} was uploaded again, media_id: {} -> {}, pages: {} -> {}",
+                old.media_id, gallery.media_id, old.pages(), gallery.pages()
+            );
+            Gallery::remove_version(out_path, old, self.args.on_update)
+                .with_context(ctx!("Failed to update gallery {id}"))
+                .inspect_err(|_| self.summary.failed())?;
+        }
+
+        match progress {
+            Some((pos, end)) => log::info!("({pos}/{end}) id: {id} [{}] pages: {}", gallery.title.pretty, gallery.pages()),
+            None => log::info!("Downloading gallery: {id} [{}] pages: {}", gallery.title.pretty, gallery.pages()),
+        }
+
+        let check_missing = !self.args.no_check_missing_pages || old.is_some();
+        self.download_pages(gallery, out_path, check_missing, selection).await
+    }
+
+    /// Downloads the pages of a gallery and records the outcome in its state file
+    async fn download_pages(
+        &self,
+        gallery: &Gallery,
+        out_path: &Path,
+        check_missing: bool,
+        selection: Option<&PageSelection>,
+    ) -> Result<()> {
+        let id = gallery.id;
+        let res = gallery.download(&self.client, self.args.network.image_url.as_ref(), out_path, self.args.overwrite, check_missing, selection).await
+            .with_context(ctx!("Failed to download gallery {id}"));
+
+        let mut state = library::GalleryState::load(out_path, id).unwrap_or_else(|e| {
+            log::warn!("Invalid state of gallery: {id}, starting a new one\nError: {e:?}");
+            Default::default()
+        });
+        // Pages left out of the selection that are still missing keep the gallery partial
+        let skipped = match selection {
+            Some(selection) if res.is_ok() => gallery.missing_pages(out_path, false, true, None).await
+                .into_iter()
+                .filter(|&i| !selection.contains(i))
+                .collect(),
+            _ => Vec::new(),
+        };
+        let recorded = match res {
+            // Nothing was checked, the gallery directory was already present
+            Ok(ref report) if report.pages.is_empty() => false,
+            Ok(ref report) => {
+                state.record(report, gallery.pages(), &skipped);
+                true
+            }
+            Err(ref e) if out_path.join(id.to_string()).is_dir() => {
+                state.record_error(format!("{e:#}"));
+                true
             }
+            Err(_) => false,
+        };
+        if recorded && let Err(e) = state.save(out_path, id) {
+            log::warn!("Failed to save the state of gallery: {id}\nError: {e:?}");
         }
 
+        let report = res.inspect_err(|_| self.summary.failed())?;
+        self.summary.transferred(report.downloaded, report.bytes);
+        match report.failed.len() {
+            // Also when nothing was checked because the gallery directory was already present
+            0 if report.downloaded == 0 => self.summary.present(),
+            0 => self.summary.downloaded(),
+            n if n == report.pages.len() => self.summary.failed(),
+            _ => self.summary.partial(),
+        }
         Ok(())
     }
+
+    /// Pages to download of the galleries, only selectable for single galleries
+    fn page_selection(&self) -> Option<&PageSelection> {
+        match self.args.action {
+            ActionType::Single(ref single) => single.pages.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Prints what downloading a gallery would do, for dry runs, all pages are needed if `updated`
+    async fn plan_gallery(&self, gallery: &Gallery, out_path: &Path, updated: bool) {
+        let missing = match updated {
+            true => (1..=gallery.pages())
+                .filter(|&i| self.page_selection().is_none_or(|s| s.contains(i)))
+                .collect(),
+            false => {
+                let check_missing = !self.args.no_check_missing_pages;
+                gallery.missing_pages(out_path, self.args.overwrite, check_missing, self.page_selection()).await
+            }
+        };
+        let bytes = match missing.is_empty() {
+            true => Some(0),
+            false => gallery.estimate_size(&self.client, self.args.network.image_url.as_ref(), &missing).await,
+        };
+        let size = match bytes {
+            Some(bytes) => format!("~{}", format_size(bytes)),
+            None => "unknown size".to_string(),
+        };
+        println!("{}\t{}/{} pages missing\t{size}\t{}", gallery.id, missing.len(), gallery.pages(), gallery.title.pretty);
+        self.summary.planned(missing.len(), bytes);
+    }
+
+}
+
+/// Parses `argv` with the options of `config`
+fn parse_with_config<I, T>(config: &Config, argv: I) -> Result<(Command, ArgMatches), clap::Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<std::ffi::OsString> + Clone,
+{
+    let (cmd, matches) = config.get_matches(&Cli::command(), argv)?;
+    // `config show` reports the conflicts instead, it's where they can be tracked down
+    if matches.subcommand_name() != Some("config") && let Err(e) = config.check_conflicts(&cmd, &matches) {
+        return Err(cmd.clone().error(ClapErrorKind::ArgumentConflict, format!("{e:#}")));
+    }
+    Ok((cmd, matches))
+}
+
+/// Parses the command line, using the config file and the environment for the missing options
+fn parse_args() -> (Cli, Config, Command, ArgMatches) {
+    // The config file and profile must be known before the real parse, because they provide the
+    // defaults of the other options
+    let pre = Cli::command()
+        .ignore_errors(true)
+        .try_get_matches()
+        .ok();
+    let config_path = pre.as_ref().and_then(|m| m.get_one::<PathBuf>("config").cloned());
+    let profile = pre.as_ref().and_then(|m| m.get_one::<String>("profile").cloned());
+
+    let config = Config::load(config_path.as_deref(), profile.as_deref())
+        .unwrap_or_else(|e| Cli::command().error(ClapErrorKind::InvalidValue, format!("{e:#}")).exit());
+    let (cmd, matches) = parse_with_config(&config, std::env::args_os())
+        .unwrap_or_else(|e| e.exit());
+    let args = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    (args, config, cmd, matches)
 }
 
 #[tokio::main]
-async fn main() {
-    let args = Cli::parse();
+async fn main() -> ExitCode {
+    let (args, config, cmd, matches) = parse_args();
     logging::init(args.verbose);
 
-    let app = match App::new(args) {
+    if let ActionType::Config(ConfigCli { action: ConfigAction::Show }) = args.action {
+        print!("{}", config.show(&cmd, &matches));
+        return Outcome::Success.into();
+    }
+
+    let blacklist = match Blacklist::load(&config, args.blacklist_file.as_deref()) {
+        Ok(blacklist) => blacklist,
+        Err(e) => {
+            log::error!("{e:?}");
+            return Outcome::Config.into();
+        }
+    };
+
+    let app = match App::new(args, blacklist).and_then(|app| app.check_args().map(|_| app)) {
         Ok(app) => app,
         Err(e) => {
             log::error!("{e:?}");
-            return;
+            return Outcome::Config.into();
         }
     };
 
     match app.run().await {
-        Ok(_) => {}
-        Err(e) => log::error!("{e:?}")
+        Ok(_) => app.summary.outcome().into(),
+        Err(e) => {
+            log::error!("{e:?}");
+            Outcome::Failure.into()
+        }
     }
 }
@@ -1,40 +1,172 @@
+use std::fmt;
 use std::num::NonZeroU32;
-use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::{Context, Result};
 
 use reqwest::{Url, Client};
 use reqwest::header;
 
-use scraper::{Html, Selector};
+use scraper::{ElementRef, Html, Selector};
 
-use crate::{SortType, ctx};
+use crate::export::ListWriter;
+use crate::gallery::Gallery;
+use crate::snapshot::Snapshot;
+use crate::{App, FavoritesCli, ListingCli, PageRangeCli, QueryCli, SortType, ctx, http};
 
 pub enum QueryResult {
     QueryList(QueryInfo, Vec<u32>),
     Gallery(u32)
 }
 
+/// Paginated list of galleries on the site
+#[derive(Clone)]
+pub enum Listing {
+    /// Results of the search page
+    Search { query: String, sort: SortType },
+    /// Favorites of the logged in user, optionally filtered with the favorites search box
+    Favorites { query: Option<String> },
+    /// Listing page of a tag, artist, group, ... (e.g. `/tag/full-color/`)
+    Tagged { kind: TagKind, slug: String, sort: SortType },
+}
+
+/// Kinds of listing pages available on the site
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TagKind {
+    Tag,
+    Artist,
+    Group,
+    Parody,
+    Character,
+    Language,
+    Category,
+}
+
+impl TagKind {
+    const ALL: [Self; 7] = [
+        Self::Tag, Self::Artist, Self::Group, Self::Parody,
+        Self::Character, Self::Language, Self::Category,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Tag => "tag",
+            Self::Artist => "artist",
+            Self::Group => "group",
+            Self::Parody => "parody",
+            Self::Character => "character",
+            Self::Language => "language",
+            Self::Category => "category",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|k| k.name() == name)
+    }
+}
+
+fn sort_path(sort: SortType) -> &'static str {
+    match sort {
+        SortType::Recent => "",
+        SortType::Popular => "popular",
+        SortType::PopularWeek => "popular-week",
+        SortType::PopularToday => "popular-today",
+    }
+}
+
+impl Listing {
+    /// Parses a listing page url (e.g. `https://nhentai.net/artist/name/popular`) or a
+    /// `type:name` pair (e.g. `tag:full color`).
+    ///
+    /// `sort` is used when the url doesn't select a sort order.
+    pub fn parse_tagged(text: &str, sort: SortType) -> Result<Self> {
+        let text = text.trim();
+        let path = match Url::parse(text) {
+            Ok(url) if url.has_host() => Some(url.path().to_string()),
+            _ if text.starts_with('/') => Some(text.to_string()),
+            _ => None,
+        };
+
+        let Some(path) = path else {
+            let (kind, name) = text.split_once(':')
+                .with_context(ctx!("Listing must be an url or a `type:name` pair, got `{text}`"))?;
+            let kind = TagKind::from_name(&kind.trim().to_lowercase())
+                .with_context(ctx!("Unknown listing type `{kind}`"))?;
+            let slug = name.trim().trim_matches('"').to_lowercase().replace(' ', "-");
+            if slug.is_empty() {
+                anyhow::bail!("Missing name in listing `{text}`");
+            }
+            return Ok(Self::Tagged { kind, slug, sort });
+        };
+
+        let mut parts = path.split('/').filter(|p| !p.is_empty());
+        let (Some(kind), Some(slug)) = (parts.next(), parts.next()) else {
+            anyhow::bail!("Listing url must be in the `/<type>/<name>/` format, got `{path}`");
+        };
+        let kind = TagKind::from_name(kind)
+            .with_context(ctx!("Unknown listing type `{kind}` in `{path}`"))?;
+        let sort = match parts.next() {
+            None => sort,
+            Some("popular") => SortType::Popular,
+            Some("popular-week") => SortType::PopularWeek,
+            Some("popular-today") => SortType::PopularToday,
+            Some(other) => anyhow::bail!("Unknown sort order `{other}` in `{path}`"),
+        };
+        if parts.next().is_some() {
+            anyhow::bail!("Unexpected path after the sort order in `{path}`");
+        }
+        Ok(Self::Tagged { kind, slug: slug.to_string(), sort })
+    }
+
+    fn url(&self, site: &Url, page: NonZeroU32) -> Url {
+        let (path, query, sort) = match self {
+            Self::Search { query, sort } => ("search/".to_string(), Some(query.as_str()), Some(*sort)),
+            Self::Favorites { query } => ("favorites/".to_string(), query.as_deref(), None),
+            Self::Tagged { kind, slug, sort } => {
+                let path = format!("{}/{slug}/{}", kind.name(), sort_path(*sort));
+                (path, None, None)
+            }
+        };
+
+        let mut url = site.join(&path).expect("listing paths are valid urls");
+        {
+            let mut pairs = url.query_pairs_mut();
+            if let Some(query) = query {
+                pairs.append_pair("q", query);
+            }
+            pairs.append_pair("page", &page.to_string());
+            if let Some(sort) = sort.filter(|s| !matches!(s, SortType::Recent)) {
+                pairs.append_pair("sort", sort_path(sort));
+            }
+        }
+        url
+    }
+}
+
+impl fmt::Display for Listing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Search { query, .. } => write!(f, "query \"{query}\""),
+            Self::Favorites { query: None } => write!(f, "favorites"),
+            Self::Favorites { query: Some(query) } => write!(f, "favorites matching \"{query}\""),
+            Self::Tagged { kind, slug, .. } => write!(f, "{} \"{slug}\"", kind.name()),
+        }
+    }
+}
+
 pub struct QueryInfo {
-    sort: SortType,
-    query: String,
+    listing: Listing,
+    site: Url,
     pages: NonZeroU32,
 }
 
 impl QueryInfo {
     pub fn pages(&self) -> NonZeroU32 { self.pages }
 
-    fn query_url(query: &str, sort: SortType, page: NonZeroU32) -> String {
-        let sort = match sort {
-            SortType::Recent => "",
-            SortType::Popular => "&sort=popular",
-            SortType::PopularWeek => "&sort=popular-week",
-            SortType::PopularToday => "&sort=popular-today"
-        };
-        format!("https://nhentai.net/search/?q={query}&page={page}{sort}")
-    }
+    pub fn listing(&self) -> &Listing { &self.listing }
 
-    fn parse_gallery_path(path: &str) -> Result<u32> {
+    /// Reads the gallery id from a gallery path (e.g. `/g/12345/`)
+    pub fn parse_gallery_path(path: &str) -> Result<u32> {
         let path = path.trim_matches('/');
         let Some(("g", code)) = path.split_once('/') else {
             anyhow::bail!("Path is not to a gallery")
@@ -43,7 +175,7 @@ impl QueryInfo {
             .with_context(ctx!("What? Gallery page code is not a number"))
     }
 
-    fn read_query_page(document: Html, query: &str, page: NonZeroU32) -> Vec<u32> {
+    fn read_query_page(document: &Html, listing: &Listing, page: NonZeroU32) -> Vec<u32> {
         let selector = Selector::parse("a.cover").unwrap();
         document
             .select(&selector)
@@ -51,29 +183,64 @@ impl QueryInfo {
             .filter_map(|(idx, e)| match e.attr("href") {
                 Some(href) => Some((idx, href)),
                 None => {
-                    log::warn!("Missing link to gallery #{}, at page {page}, query: \"{query}\"", idx + 1);
+                    log::warn!("Missing link to gallery #{}, at page {page}, {listing}", idx + 1);
                     None
                 }
             })
             .filter_map(|(idx, href)| match Self::parse_gallery_path(href) {
                 Ok(code) => Some(code),
                 Err(_) => {
-                    log::warn!("Invalid link to gallery #{}, at page {page}, query: \"{query}\"", idx + 1);
+                    log::warn!("Invalid link to gallery #{}, at page {page}, {listing}", idx + 1);
                     None
                 }
             })
             .collect()
     }
 
-    pub async fn load(client: &Client, query: &str, sort: SortType, page: NonZeroU32) -> Result<QueryResult> {
-        let url = Self::query_url(query, sort, page);
+    fn read_page_link(link: ElementRef, site: &Url, url: &Url) -> Result<NonZeroU32> {
+        let href = link.attr("href")
+            .with_context(ctx!("What? Missing href on pagination button, URL: {url}"))?;
+
+        let href = site.join(href)
+            .with_context(ctx!("What? Invalid url for pagination button, href: {href}, URL: {url}"))?;
+
+        let (_, page) = href.query_pairs()
+            .find(|(key, _)| key == "page")
+            .with_context(ctx!("What? Missing page for pagination button, href: {href}, URL: {url}"))?;
+
+        page.parse()
+            .with_context(ctx!("What? Cannot parse page number of pagination button"))
+    }
+
+    fn read_last_page(document: &Html, site: &Url, url: &Url) -> Result<NonZeroU32> {
+        let last_page_selector = Selector::parse("a.last").unwrap();
+        let mut last_page_iter = document.select(&last_page_selector);
+        let Some(last_page) = last_page_iter.next() else {
+            // Listings with a single page have no pagination at all
+            let pagination_selector = Selector::parse("section.pagination").unwrap();
+            if document.select(&pagination_selector).next().is_none() {
+                return Ok(NonZeroU32::MIN);
+            }
+            // The last page has no last page button, only the numbered ones
+            let page_selector = Selector::parse("section.pagination a.page").unwrap();
+            return document.select(&page_selector)
+                .map(|link| Self::read_page_link(link, site, url))
+                .reduce(|a, b| Ok(a?.max(b?)))
+                .with_context(ctx!("What? Missing last page button on query page, URL: {url}"))?;
+        };
+        if last_page_iter.next().is_some() {
+            log::warn!("What? Multiple last page buttons on query page, using first, URL: {url}");
+        }
+
+        Self::read_page_link(last_page, site, url)
+    }
+
+    pub async fn load(client: &Client, site: &Url, listing: Listing, page: NonZeroU32) -> Result<QueryResult> {
+        let url = listing.url(site, page);
 
         log::trace!("Connecting to query page: {url}");
-        let res = client.get(&url)
-            .send().await
-            .with_context(ctx!("Failed to retrive query page at {url}"))?
-            .error_for_status()
-            .with_context(ctx!("Received error from nhentai at {url}"))?;
+        let res = http::get(client, url.as_str()).await
+            .with_context(ctx!("Failed to retrive query page at {url}"))?;
 
         if res.status().is_redirection() {
             log::trace!("Query page at {url} is a redirection, opening gallery");
@@ -87,69 +254,402 @@ impl QueryInfo {
                 Ok(url) => url.path().to_string(),
                 Err(_) => location.to_string()
             };
-            
+
             Self::parse_gallery_path(&location)
-                .with_context(ctx!("What? Redirect from query is not a gallery, Redirect: {location}, Query: {query}"))
+                .with_context(ctx!("What? Redirect from query is not a gallery, Redirect: {location}, {listing}"))
                 .map(QueryResult::Gallery)
         } else {
             log::trace!("Parsing query page at {url}");
 
-            let text = res.text().await
+            let text = http::text(res, url.as_str()).await
                 .with_context(ctx!("Failed to retrive query page contents, URL: {url}"))?;
             let document = Html::parse_document(&text);
 
-            // Find the last page number
-            let last_page_selector = Selector::parse("a.last").unwrap();
-            let mut last_page_iter = document.select(&last_page_selector);
-            let last_page = last_page_iter.next()
-                .with_context(ctx!("What? Missing last page button on query page, URL: {url}"))?;
-            if last_page_iter.next().is_some() {
-                log::warn!("What? Multiple last page buttons on query page, using first, URL: {url}");
-            }
-
-            let last_href = last_page.attr("href")
-                .with_context(ctx!("What? Missing href on last page button, URL: {url}"))?;
-
-            let base = Url::from_str("https://nhentai.net").unwrap();
-            let last_href = base.join(last_href)
-                .with_context(ctx!("What? Invalid url for last page button, href: {last_href}, URL: {url}"))?;
-
-            let (_, last_page) = last_href.query_pairs()
-                .find(|(key, _)| key == "page")
-                .with_context(ctx!("What? Missing page for last page button, href: {last_href}, URL: {url}"))?;
+            let last_page = Self::read_last_page(&document, site, &url)?;
+            log::trace!("{listing} has {last_page} pages");
 
-            let last_page: NonZeroU32 = last_page.parse()
-                .with_context(ctx!("What? Cannot parse last page number"))?;
-
-            log::trace!("Query: \"{query}\" has {last_page} pages");
+            let galleries = Self::read_query_page(&document, &listing, page);
+            log::trace!("Found {} galleries on page {page} of {listing}", galleries.len());
 
             let s = Self {
-                query: query.to_string(),
-                sort,
+                listing,
+                site: site.clone(),
                 pages: last_page,
             };
-
-            let galleries = Self::read_query_page(document, query, page);
-            log::trace!("Found {} galleries on page {page} of query {query}", galleries.len());
             Ok(QueryResult::QueryList(s, galleries))
         }
     }
 
     pub async fn load_page(&self, client: &Client, page: NonZeroU32) -> Result<Vec<u32>> {
-        let url = Self::query_url(&self.query, self.sort, page);
+        let url = self.listing.url(&self.site, page);
 
         log::trace!("Connecting to query page: {url}");
-        let text = client.get(&url)
-            .send().await
-            .with_context(ctx!("Failed to retrive query page at {url}"))?
-            .error_for_status()
-            .with_context(ctx!("Received error from nhentai at {url}"))?
-            .text().await
+        let res = http::get(client, url.as_str()).await
+            .with_context(ctx!("Failed to retrive query page at {url}"))?;
+        let text = http::text(res, url.as_str()).await
             .with_context(ctx!("Failed to retrive query page contents, URL: {url}"))?;
 
         let document = Html::parse_document(&text);
-        let galleries = Self::read_query_page(document, &self.query, page);
-        log::trace!("Found {} galleries on page {page} of query {}", galleries.len(), &self.query);
+        let galleries = Self::read_query_page(&document, &self.listing, page);
+        log::trace!("Found {} galleries on page {page} of {}", galleries.len(), self.listing);
         Ok(galleries)
     }
 }
+
+impl App {
+    /// Checks if a gallery is seen for the first time in this run, duplicates are logged and counted
+    fn first_seen(&self, id: u32) -> bool {
+        let first = self.seen.lock().unwrap().insert(id);
+        if !first {
+            log::debug!("Skipping gallery: {id}, already handled in this run");
+            self.summary.duplicate();
+        }
+        first
+    }
+
+    pub async fn download_query(&self, query: &QueryCli) -> Result<()> {
+        let queries = query.queries()?;
+        let mut writer = match query.list.list {
+            true => Some(query.list.writer()?),
+            false => None,
+        };
+
+        let mut breakdown = Vec::new();
+        for (text, sort, range) in &queries {
+            if queries.len() > 1 {
+                log::info!(">>> Query \"{text}\"");
+            }
+            let before = self.summary.counts();
+            let res = match query.split_dates {
+                true => self.download_split_query(text, *sort, range, query.split_pages, writer.as_mut()).await,
+                false => self.download_one_query(text, *sort, range, writer.as_mut()).await,
+            };
+            let counts = self.summary.counts().since(before);
+            match res {
+                Err(e) if http::is_blocked(&e) || queries.len() == 1 => return Err(e),
+                Err(e) => {
+                    log::warn!("Failed to download query `{text}`\nError: {e:?}");
+                    breakdown.push(format!("\"{text}\": query failed, {counts}"));
+                }
+                Ok(()) => breakdown.push(format!("\"{text}\": {counts}")),
+            }
+        }
+
+        if queries.len() > 1 {
+            log::info!("Queries:");
+            for line in breakdown {
+                log::info!("  {line}");
+            }
+        }
+        match writer {
+            Some(writer) => writer.finish(),
+            None => Ok(()),
+        }
+    }
+
+    async fn download_one_query(
+        &self,
+        text: &str,
+        sort: SortType,
+        range: &PageRangeCli,
+        writer: Option<&mut ListWriter>,
+    ) -> Result<()> {
+        let listing = Listing::Search { query: text.to_string(), sort };
+        let query_res = QueryInfo::load(&self.client, &self.args.network.site_url, listing, range.first_page).await
+            .with_context(ctx!("Failed to load query `{text}`"))?;
+
+        let (query_info, galleries) = match query_res {
+            QueryResult::Gallery(id) => {
+                log::info!("The provided query points to a single gallery");
+                if !self.first_seen(id) {
+                    return Ok(());
+                }
+                return match writer {
+                    Some(writer) => self.list_galleries(writer, &[id]).await,
+                    None => self.download_gallery(id, None).await,
+                };
+            }
+            QueryResult::QueryList(i, g) => (i, g)
+        };
+
+        let mut snapshot = Snapshot::new(text, sort);
+        self.download_listing(&query_info, galleries, range, false, writer, Some(&mut snapshot)).await?;
+
+        if !self.args.dry_run {
+            match snapshot.save() {
+                Ok(path) => log::debug!("Saved query snapshot to {path:?}"),
+                Err(e) => log::warn!("Failed to save query snapshot\nError: {e:?}"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Downloads a query in consecutive `uploaded:` windows with at most `max_pages` pages each
+    async fn download_split_query(
+        &self,
+        text: &str,
+        sort: SortType,
+        range: &PageRangeCli,
+        max_pages: NonZeroU32,
+        mut writer: Option<&mut ListWriter>,
+    ) -> Result<()> {
+        // No gallery is older than the site
+        const SITE_START: u64 = 1401580800; // 2014-06-01
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+        let total_days = now.saturating_sub(SITE_START) / 86400 + 1;
+        let max_pages = u64::from(max_pages.get());
+        let window_range = PageRangeCli { count: Some(0), ..range.clone() };
+
+        // Window of ages in days, from `start` (newest) to `start + size`
+        let mut start = 0;
+        let mut size = 7;
+        while start < total_days {
+            let end = start + size;
+            // Windows overlap by a day, as the site rounds the ages, duplicates are skipped
+            let window = match start {
+                0 => format!("{text} uploaded:<{end}d"),
+                _ => format!("{text} uploaded:>{}d uploaded:<{end}d", start - 1),
+            };
+
+            let listing = Listing::Search { query: window.clone(), sort };
+            let query_res = QueryInfo::load(&self.client, &self.args.network.site_url, listing, NonZeroU32::MIN).await
+                .with_context(ctx!("Failed to load query `{window}`"))?;
+            let (query_info, galleries) = match query_res {
+                QueryResult::Gallery(id) => {
+                    log::info!("Window {start}-{end} days ago has a single gallery");
+                    if self.first_seen(id) {
+                        match writer.as_deref_mut() {
+                            Some(writer) => self.list_galleries(writer, &[id]).await?,
+                            None => self.download_gallery(id, None).await?,
+                        }
+                    }
+                    start = end;
+                    continue;
+                }
+                QueryResult::QueryList(i, g) => (i, g),
+            };
+
+            let pages = u64::from(query_info.pages().get());
+            if pages > max_pages && size > 1 {
+                size = (size / 2).max(1);
+                log::debug!("Window {start}-{end} days ago has {pages} pages, trying {size} days");
+                continue;
+            }
+            if pages > max_pages {
+                log::warn!("Window {start}-{end} days ago still has {pages} pages, some galleries may be cut off");
+            }
+
+            log::info!(">>> Window {start}-{end} days ago");
+            self.download_listing(&query_info, galleries, &window_range, true, writer.as_deref_mut(), None).await?;
+
+            // Aim for half the maximum pages in the next window, without growing too fast
+            start = end;
+            size = (size * max_pages / 2 / pages).clamp(1, size * 4);
+        }
+        Ok(())
+    }
+
+    pub async fn download_favorites(&self, favorites: &FavoritesCli) -> Result<()> {
+        let listing = Listing::Favorites { query: favorites.query.clone() };
+        let query_res = QueryInfo::load(&self.client, &self.args.network.site_url, listing, favorites.range.first_page).await
+            .with_context(ctx!("Failed to load favorites"))?;
+
+        let QueryResult::QueryList(query_info, galleries) = query_res else {
+            anyhow::bail!("What? Favorites page redirected to a gallery");
+        };
+
+        let mut writer = match favorites.list.list {
+            true => Some(favorites.list.writer()?),
+            false => None,
+        };
+        self.download_listing(&query_info, galleries, &favorites.range, true, writer.as_mut(), None).await?;
+        match writer {
+            Some(writer) => writer.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn download_tagged(&self, listing: &ListingCli) -> Result<()> {
+        let parsed = Listing::parse_tagged(&listing.listing, listing.sort)?;
+        let query_res = QueryInfo::load(&self.client, &self.args.network.site_url, parsed, listing.range.first_page).await
+            .with_context(ctx!("Failed to load listing `{}`", listing.listing))?;
+
+        let QueryResult::QueryList(query_info, galleries) = query_res else {
+            anyhow::bail!("What? Listing page redirected to a gallery");
+        };
+
+        let mut writer = match listing.list.list {
+            true => Some(listing.list.writer()?),
+            false => None,
+        };
+        self.download_listing(&query_info, galleries, &listing.range, false, writer.as_mut(), None).await?;
+        match writer {
+            Some(writer) => writer.finish(),
+            None => Ok(()),
+        }
+    }
+
+    /// Downloads the galleries of the selected pages of a listing, or writes them to `writer`.
+    ///
+    /// `galleries` are the galleries of the first page, that were read when loading `query_info`.
+    /// The visited pages are recorded in `snapshot`.
+    async fn download_listing(
+        &self,
+        query_info: &QueryInfo,
+        galleries: Vec<u32>,
+        range: &PageRangeCli,
+        all_pages: bool,
+        mut writer: Option<&mut ListWriter>,
+        mut snapshot: Option<&mut Snapshot>,
+    ) -> Result<()> {
+        let listing = query_info.listing();
+        if query_info.pages() < range.first_page {
+            anyhow::bail!("The first page must be less that the number of pages (it's {})", query_info.pages());
+        }
+
+        log::info!("Found {} pages available for {listing}", query_info.pages());
+
+        let last_page = range.last_page(query_info.pages(), all_pages || range.until_known || range.reverse);
+        let mut known_run = 0;
+        let mut handled = 0;
+
+        let mut pages: Vec<u32> = (range.first_page.get()..=last_page.get()).collect();
+        if range.reverse {
+            pages.reverse();
+        }
+
+        let mut first_page_galleries = Some(galleries);
+        for (pos, &page) in pages.iter().enumerate() {
+            // SAFETY: None of the numbers between two non-zero numbers are zero.
+            let page = unsafe { NonZeroU32::new_unchecked(page) };
+
+            let preloaded = match page == range.first_page {
+                true => first_page_galleries.take(),
+                false => None,
+            };
+            let mut galleries = match preloaded {
+                Some(g) => g,
+                None => match query_info.load_page(&self.client, page).await {
+                    Ok(g) => g,
+                    Err(e) if http::is_blocked(&e) => return Err(e),
+                    Err(e) => {
+                        log::warn!("Failed to download query page: {page}\nError: {e:?}");
+                        self.summary.failed_page();
+                        continue;
+                    }
+                }
+            };
+
+            if let Some(ref mut snapshot) = snapshot {
+                snapshot.add_page(page, &galleries);
+            }
+            if range.reverse {
+                galleries.reverse();
+            }
+
+            let mut stop = false;
+            if range.until_known {
+                let mut unknown = Vec::with_capacity(galleries.len());
+                for gallery in galleries {
+                    if !Gallery::is_downloaded(self.out_path()?, gallery).await {
+                        known_run = 0;
+                        unknown.push(gallery);
+                        continue;
+                    }
+                    log::debug!("Skipping known gallery: {gallery}");
+                    known_run += 1;
+                    if known_run >= range.known_run.get() {
+                        stop = true;
+                        break;
+                    }
+                }
+                galleries = unknown;
+            }
+
+            if writer.is_none() {
+                log::info!(">>> ({}/{}) Downloading query page #{page}", pos + 1, pages.len());
+            }
+            let mut limit_reached = false;
+            let gallery_count = galleries.len();
+            for (i, gallery) in galleries.into_iter().enumerate() {
+                if !self.first_seen(gallery) {
+                    continue;
+                }
+
+                let before = self.summary.counts();
+                if let Some(ref mut writer) = writer {
+                    self.list_galleries(writer, &[gallery]).await?;
+                } else {
+                    match self.download_gallery(gallery, Some((i + 1, gallery_count))).await {
+                        Err(e) if http::is_blocked(&e) => return Err(e),
+                        Err(e) => log::warn!("Failed to download gallery: {gallery}\nError: {e:?}"),
+                        Ok(()) => {}
+                    }
+                }
+
+                // Galleries excluded by the blacklist or the filters don't count toward the limit
+                let counts = self.summary.counts().since(before);
+                if counts.filtered + counts.blacklisted == 0 {
+                    handled += 1;
+                }
+                if range.max_galleries.is_some_and(|max| handled >= max.get()) {
+                    limit_reached = true;
+                    break;
+                }
+            }
+
+            if stop {
+                log::info!("Found {known_run} known galleries in a row at page {page}, stopping");
+                break;
+            }
+            if limit_reached {
+                log::info!("Reached the limit of {handled} galleries at page {page}, stopping");
+                break;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use reqwest::Url;
+    use scraper::Html;
+
+    use super::{Listing, QueryInfo};
+
+    const PAGE1: &str = include_str!("../tests/fixtures/favorites/page1.html");
+    const PAGE2: &str = include_str!("../tests/fixtures/favorites/page2.html");
+    const PAGE3: &str = include_str!("../tests/fixtures/favorites/page3.html");
+    const SEARCH: &str = include_str!("../tests/fixtures/favorites/search.html");
+
+    fn read(text: &str, listing: &Listing, page: u32) -> (u32, Vec<u32>) {
+        let site = Url::parse("https://nhentai.net/").unwrap();
+        let page = NonZeroU32::new(page).unwrap();
+        let url = listing.url(&site, page);
+        let document = Html::parse_document(text);
+        let last_page = QueryInfo::read_last_page(&document, &site, &url).unwrap();
+        (last_page.get(), QueryInfo::read_query_page(&document, listing, page))
+    }
+
+    #[test]
+    fn favorites_pages() {
+        let listing = Listing::Favorites { query: None };
+        assert_eq!(read(PAGE1, &listing, 1), (3, vec![485021, 484800, 483117, 480002, 477765]));
+        assert_eq!(read(PAGE2, &listing, 2), (3, vec![470123, 468990, 461234, 455555, 450001]));
+        // The last page only has the numbered page buttons
+        assert_eq!(read(PAGE3, &listing, 3), (3, vec![401010]));
+    }
+
+    #[test]
+    fn favorites_search() {
+        let site = Url::parse("https://nhentai.net/").unwrap();
+        let listing = Listing::Favorites { query: Some("artist:someone".to_string()) };
+        let url = listing.url(&site, NonZeroU32::MIN);
+        assert_eq!(url.as_str(), "https://nhentai.net/favorites/?q=artist%3Asomeone&page=1");
+        assert_eq!(read(SEARCH, &listing, 1), (1, vec![484800, 455555]));
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fmt::Write as _;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{App, RelatedCli, ctx, http};
+
+/// State of a breadth first crawl of the related galleries, saved after every gallery so an
+/// interrupted crawl can continue
+#[derive(Serialize, Deserialize)]
+pub struct Crawl {
+    roots: Vec<u32>,
+    /// Galleries to visit, with their distance from the roots
+    queue: VecDeque<(u32, u32)>,
+    /// Galleries that were queued, visited or failed
+    seen: HashSet<u32>,
+    /// Related galleries of each visited gallery
+    edges: BTreeMap<u32, Vec<u32>>,
+    failed: Vec<u32>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl Crawl {
+    /// Default location of the state file of a crawl starting from `roots`
+    pub fn default_path(roots: &[u32]) -> Option<PathBuf> {
+        let name: Vec<_> = roots.iter().map(u32::to_string).collect();
+        crate::config::data_dir().map(|dir| dir.join("related").join(format!("{}.json", name.join("-"))))
+    }
+
+    fn new(roots: &[u32], path: &Path) -> Self {
+        let mut seen = HashSet::new();
+        let queue = roots.iter()
+            .filter(|id| seen.insert(**id))
+            .map(|id| (*id, 0))
+            .collect();
+        Self {
+            roots: roots.to_vec(),
+            queue,
+            seen,
+            edges: BTreeMap::new(),
+            failed: Vec::new(),
+            path: path.to_path_buf(),
+        }
+    }
+
+    /// Continues the crawl saved in `path`, or starts a new one if there is none (or `restart` is set)
+    pub fn load(path: &Path, roots: &[u32], restart: bool) -> Result<Self> {
+        if restart {
+            return Ok(Self::new(roots, path));
+        }
+
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Self::new(roots, path)),
+            Err(e) => return Err(e).with_context(ctx!("Cannot read crawl state {path:?}")),
+        };
+
+        let mut crawl: Self = serde_json::from_str(&text)
+            .with_context(ctx!("Invalid crawl state {path:?}"))?;
+        if crawl.roots != roots {
+            anyhow::bail!("Crawl state {path:?} belongs to a crawl starting from {:?}", crawl.roots);
+        }
+        crawl.path = path.to_path_buf();
+
+        if crawl.queue.is_empty() {
+            log::info!(
+                "Crawl is already complete, {} galleries visited, pass `--restart` to crawl again",
+                crawl.visited()
+            );
+        } else {
+            log::info!("Continuing crawl: {} galleries visited, {} queued", crawl.edges.len(), crawl.queue.len());
+        }
+        Ok(crawl)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(ctx!("Cannot create directory {parent:?}"))?;
+        }
+        let json = serde_json::to_vec(self)
+            .with_context(ctx!("Cannot serialize crawl state"))?;
+        std::fs::write(&self.path, json)
+            .with_context(ctx!("Cannot write crawl state {:?}", self.path))
+    }
+
+    /// Next gallery to visit and its depth
+    pub fn next(&self) -> Option<(u32, u32)> {
+        self.queue.front().copied()
+    }
+
+    pub fn visited(&self) -> usize {
+        self.edges.len() + self.failed.len()
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Marks the next gallery as visited, queueing its related galleries that are within
+    /// `max_depth` without exceeding `limit` galleries in total.
+    pub fn visit(&mut self, related: &[u32], max_depth: u32, limit: usize) {
+        let Some((id, depth)) = self.queue.pop_front() else { return };
+
+        if depth < max_depth {
+            for &rel in related {
+                if self.seen.len() >= limit {
+                    break;
+                }
+                if self.seen.insert(rel) {
+                    self.queue.push_back((rel, depth + 1));
+                }
+            }
+        }
+        self.edges.insert(id, related.to_vec());
+    }
+
+    /// Marks the next gallery as failed, it won't be visited again
+    pub fn fail(&mut self) {
+        if let Some((id, _)) = self.queue.pop_front() {
+            self.failed.push(id);
+        }
+    }
+
+    /// Graph of the visited galleries in the graphviz dot format
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph related {\n");
+        for root in &self.roots {
+            let _ = writeln!(out, "    {root} [shape=box];");
+        }
+        for id in &self.failed {
+            let _ = writeln!(out, "    {id} [style=dashed];");
+        }
+        for (id, related) in &self.edges {
+            for rel in related {
+                let _ = writeln!(out, "    {id} -> {rel};");
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+impl App {
+    pub async fn crawl_related(&self, related: &RelatedCli) -> Result<()> {
+        let state_path = match related.state {
+            Some(ref path) => path.clone(),
+            None => Crawl::default_path(&related.ids)
+                .with_context(ctx!("Cannot find the user data directory, pass `--state`"))?,
+        };
+        let mut crawl = Crawl::load(&state_path, &related.ids, related.restart)?;
+
+        while let Some((id, depth)) = crawl.next() {
+            let visited = crawl.visited() + 1;
+            let total = visited + crawl.queued() - 1;
+
+            // The related galleries are expanded once the gallery is loaded, even if it's not
+            // downloaded because it's excluded or its download failed
+            let res = match self.load_gallery(id).await.inspect_err(|_| self.summary.failed()) {
+                Ok(gallery) => {
+                    let res = match related.graph {
+                        true => Ok(()),
+                        false => self.download_loaded_gallery(&gallery, Some((visited, total))).await,
+                    };
+                    match res {
+                        Err(e) if http::is_blocked(&e) => Err(e),
+                        res => {
+                            if let Err(e) = res {
+                                log::warn!("Failed to download gallery: {id}\nError: {e:?}");
+                            }
+                            log::debug!("Visited gallery {id} at depth {depth}, {} related", gallery.related.len());
+                            crawl.visit(&gallery.related, related.depth, related.limit);
+                            Ok(())
+                        }
+                    }
+                }
+                Err(e) => Err(e),
+            };
+
+            match res {
+                Ok(()) => {}
+                Err(e) if http::is_blocked(&e) => {
+                    if !self.args.dry_run {
+                        crawl.save()?;
+                    }
+                    return Err(e);
+                }
+                Err(e) => {
+                    log::warn!("Failed to crawl gallery: {id}\nError: {e:?}");
+                    crawl.fail();
+                }
+            }
+
+            if self.args.dry_run {
+                continue;
+            }
+            if let Err(e) = crawl.save() {
+                log::warn!("Failed to save crawl state\nError: {e:?}");
+            }
+        }
+
+        log::info!("Crawl finished, {} galleries visited", crawl.visited());
+        if related.graph {
+            print!("{}", crawl.to_dot());
+        }
+        Ok(())
+    }
+}
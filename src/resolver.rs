@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+
+use crate::ctx;
+
+/// Which address family to use when a host has both
+#[derive(clap::ValueEnum, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum IpPreference {
+    /// Use the addresses in the order returned by the system
+    #[default]
+    Auto,
+    /// Try IPv4 addresses first
+    Ipv4,
+    /// Try IPv6 addresses first
+    Ipv6,
+    /// Use only IPv4 addresses
+    Ipv4Only,
+    /// Use only IPv6 addresses
+    Ipv6Only,
+}
+
+/// DNS resolver that applies static overrides and address family preference
+pub struct Resolver {
+    overrides: Arc<HashMap<String, Vec<IpAddr>>>,
+    preference: IpPreference,
+}
+
+impl Resolver {
+    pub fn new(overrides: &[(String, IpAddr)], preference: IpPreference) -> Self {
+        let mut map: HashMap<String, Vec<IpAddr>> = HashMap::new();
+        for (host, ip) in overrides {
+            map.entry(host.to_ascii_lowercase()).or_default().push(*ip);
+        }
+        Self { overrides: Arc::new(map), preference }
+    }
+
+    fn sort(preference: IpPreference, mut addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        match preference {
+            IpPreference::Auto => {}
+            // sort_by_key is stable, so the system order is kept inside each family
+            IpPreference::Ipv4 => addrs.sort_by_key(|a| !a.is_ipv4()),
+            IpPreference::Ipv6 => addrs.sort_by_key(|a| !a.is_ipv6()),
+            IpPreference::Ipv4Only => addrs.retain(|a| a.is_ipv4()),
+            IpPreference::Ipv6Only => addrs.retain(|a| a.is_ipv6()),
+        }
+        addrs
+    }
+}
+
+impl Resolve for Resolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let overrides = Arc::clone(&self.overrides);
+        let preference = self.preference;
+        Box::pin(async move {
+            let host = name.as_str().to_ascii_lowercase();
+            let addrs: Vec<SocketAddr> = match overrides.get(&host) {
+                Some(ips) => {
+                    log::trace!("Using static address override for {host}: {ips:?}");
+                    ips.iter().map(|ip| SocketAddr::new(*ip, 0)).collect()
+                }
+                None => tokio::net::lookup_host((host.as_str(), 0)).await?.collect(),
+            };
+
+            let addrs = Self::sort(preference, addrs);
+            if addrs.is_empty() {
+                return Err(format!("No usable address found for {host} ({preference:?})").into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Parses an address override in the `host=ip` format
+pub fn parse_override(text: &str) -> Result<(String, IpAddr)> {
+    let (host, ip) = text.split_once('=')
+        .with_context(ctx!("Address override must be in the `host=ip` format"))?;
+    let ip = ip.trim().trim_start_matches('[').trim_end_matches(']');
+    let ip = ip.parse()
+        .with_context(ctx!("Invalid ip address `{ip}`"))?;
+    Ok((host.trim().to_string(), ip))
+}
@@ -0,0 +1,195 @@
+use std::collections::{BTreeMap, HashMap};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{App, QueryDiffCli, SortType, ctx};
+
+/// Result list of a query at a point in time, recorded on every run to compare it with later ones
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub query: String,
+    pub sort: SortType,
+    /// Unix timestamp of the run
+    pub taken: u64,
+    /// Galleries of each visited page, in the order shown on the site
+    pub pages: BTreeMap<u32, Vec<u32>>,
+}
+
+/// Changes between two snapshots of a query, ranks start from 1
+#[derive(Default)]
+pub struct Diff {
+    /// Pages present in both snapshots, the only ones compared
+    pub pages: Vec<u32>,
+    pub added: Vec<(u32, usize)>,
+    pub removed: Vec<(u32, usize)>,
+    /// Galleries with a different rank, with the old and the new one
+    pub moved: Vec<(u32, usize, usize)>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/// Formats how long ago a unix timestamp was (e.g. `3 days ago`)
+pub fn format_age(timestamp: u64) -> String {
+    let secs = now().saturating_sub(timestamp);
+    let (value, unit) = match secs {
+        0..3600 => (secs / 60, "minutes"),
+        3600..86400 => (secs / 3600, "hours"),
+        _ => (secs / 86400, "days"),
+    };
+    format!("{value} {unit} ago")
+}
+
+impl Snapshot {
+    pub fn new(query: &str, sort: SortType) -> Self {
+        Self { query: query.to_string(), sort, taken: now(), pages: BTreeMap::new() }
+    }
+
+    pub fn add_page(&mut self, page: NonZeroU32, galleries: &[u32]) {
+        self.pages.insert(page.get(), galleries.to_vec());
+    }
+
+    /// Directory with the snapshots of a query (e.g.
+    /// `~/.local/share/nhentai-downloader/snapshots/tag_full_color.popular`)
+    pub fn dir(query: &str, sort: SortType) -> Option<PathBuf> {
+        let name: String = query.trim().chars()
+            .map(|c| if c.is_alphanumeric() || c == '-' { c } else { '_' })
+            .collect();
+        let sort = clap::ValueEnum::to_possible_value(&sort).expect("no skipped sort types");
+        crate::config::data_dir()
+            .map(|dir| dir.join("snapshots").join(format!("{name}.{}", sort.get_name())))
+    }
+
+    /// Saves the snapshot in the directory of its query, named after the time it was taken
+    pub fn save(&self) -> Result<PathBuf> {
+        let dir = Self::dir(&self.query, self.sort)
+            .with_context(ctx!("Cannot find the user data directory"))?;
+        std::fs::create_dir_all(&dir)
+            .with_context(ctx!("Cannot create directory {dir:?}"))?;
+        let path = dir.join(format!("{}.json", self.taken));
+        let json = serde_json::to_vec(self)
+            .with_context(ctx!("Cannot serialize query snapshot"))?;
+        std::fs::write(&path, json)
+            .with_context(ctx!("Cannot write query snapshot {path:?}"))?;
+        Ok(path)
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(ctx!("Cannot read query snapshot {path:?}"))?;
+        serde_json::from_str(&text)
+            .with_context(ctx!("Invalid query snapshot {path:?}"))
+    }
+
+    /// Saved snapshots of a query, from the oldest
+    pub fn list(query: &str, sort: SortType) -> Result<Vec<PathBuf>> {
+        let dir = Self::dir(query, sort)
+            .with_context(ctx!("Cannot find the user data directory"))?;
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(ctx!("Cannot read directory {dir:?}")),
+        };
+
+        let mut snapshots: Vec<(u64, PathBuf)> = entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter_map(|path| {
+                let taken = path.file_stem()?.to_str()?.parse().ok()?;
+                Some((taken, path))
+            })
+            .collect();
+        snapshots.sort();
+        Ok(snapshots.into_iter().map(|(_, path)| path).collect())
+    }
+
+    /// Ranks of the galleries in `pages`
+    fn ranks(&self, pages: &[u32]) -> HashMap<u32, usize> {
+        let mut ranks = HashMap::new();
+        let galleries = pages.iter().flat_map(|p| &self.pages[p]);
+        for (rank, &id) in galleries.enumerate() {
+            ranks.entry(id).or_insert(rank + 1);
+        }
+        ranks
+    }
+
+    /// Compares the pages visited by both snapshots, `self` is the older one
+    pub fn diff(&self, new: &Self) -> Diff {
+        let pages: Vec<u32> = self.pages.keys()
+            .filter(|p| new.pages.contains_key(p))
+            .copied()
+            .collect();
+        let old_ranks = self.ranks(&pages);
+        let new_ranks = new.ranks(&pages);
+
+        let mut diff = Diff { pages, ..Default::default() };
+        for (&id, &rank) in &new_ranks {
+            match old_ranks.get(&id) {
+                None => diff.added.push((id, rank)),
+                Some(&old) if old != rank => diff.moved.push((id, old, rank)),
+                Some(_) => {}
+            }
+        }
+        for (&id, &rank) in &old_ranks {
+            if !new_ranks.contains_key(&id) {
+                diff.removed.push((id, rank));
+            }
+        }
+
+        diff.added.sort_by_key(|&(_, rank)| rank);
+        diff.removed.sort_by_key(|&(_, rank)| rank);
+        diff.moved.sort_by_key(|&(_, _, rank)| rank);
+        diff
+    }
+}
+
+impl App {
+    pub fn diff_query(diff: &QueryDiffCli) -> Result<()> {
+        let saved = Snapshot::list(&diff.query, diff.sort)?;
+        let mut latest = saved.iter().rev();
+        let new = match diff.new {
+            Some(ref path) => path,
+            None => latest.next()
+                .with_context(ctx!("No snapshots saved for query `{}`, download it first", diff.query))?,
+        };
+        let old = match diff.old {
+            Some(ref path) => path,
+            None => latest.next()
+                .with_context(ctx!("Only one snapshot saved for query `{}`, download it again first", diff.query))?,
+        };
+        let (old, new) = (Snapshot::load(old)?, Snapshot::load(new)?);
+
+        let changes = old.diff(&new);
+        println!(
+            "Query \"{}\": snapshot of {} ({}) -> {} ({})",
+            new.query, old.taken, format_age(old.taken), new.taken, format_age(new.taken),
+        );
+        if changes.pages.is_empty() {
+            println!("The snapshots have no pages in common");
+            return Ok(());
+        }
+        println!("Compared pages: {:?}", changes.pages);
+
+        println!("\nAdded ({}):", changes.added.len());
+        for (id, rank) in changes.added {
+            println!("  {id} at rank {rank}");
+        }
+        println!("\nRemoved, possibly deleted from the site ({}):", changes.removed.len());
+        for (id, rank) in changes.removed {
+            println!("  {id} was at rank {rank}");
+        }
+        // In recent sort the ranks move down with every new gallery
+        if !matches!(new.sort, SortType::Recent) {
+            println!("\nMoved ({}):", changes.moved.len());
+            for (id, old, new) in changes.moved {
+                println!("  {id} rank {old} -> {new} ({:+})", old as i64 - new as i64);
+            }
+        }
+        Ok(())
+    }
+}
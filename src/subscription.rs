@@ -0,0 +1,408 @@
+use std::collections::BTreeSet;
+use std::io::ErrorKind;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::filter::Filter;
+use crate::query::{Listing, QueryInfo, QueryResult};
+use crate::{App, SortType, SubAction, SubCli, SyncCli, ctx, http};
+
+/// A saved query that `sync` keeps up to date
+#[derive(Serialize, Deserialize)]
+pub struct Subscription {
+    pub name: String,
+    pub query: String,
+    pub sort: SortType,
+    /// Filter expressions, checked together with the ones of the command line
+    #[serde(default)]
+    pub filters: Vec<String>,
+    /// Output directory, instead of the one of the command line
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// Profile of the config file used to sync this subscription, instead of the one of the command line
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Newest gallery handled by the previous sync, with the recent sort only newer galleries are
+    /// downloaded
+    #[serde(default)]
+    pub last_seen: Option<u32>,
+    /// Galleries handled by the previous syncs, used with the other sorts where older galleries can
+    /// show up at any time
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub seen: BTreeSet<u32>,
+    /// Number of pages checked by each sync with the other sorts
+    #[serde(default = "first_page")]
+    pub pages: NonZeroU32,
+    /// Unix timestamp of the previous sync
+    #[serde(default)]
+    pub last_sync: Option<u64>,
+}
+
+fn first_page() -> NonZeroU32 {
+    NonZeroU32::MIN
+}
+
+impl Subscription {
+    /// Checks if a gallery was handled by a previous sync
+    pub fn is_seen(&self, id: u32) -> bool {
+        match self.sort {
+            // Recent galleries are sorted by id, so everything up to the newest one was seen
+            SortType::Recent => self.last_seen.is_some_and(|last| id <= last),
+            _ => self.seen.contains(&id),
+        }
+    }
+
+    /// Checks if a sync can stop after `page`, with the `galleries` on it
+    pub fn is_last_page(&self, page: NonZeroU32, galleries: &[u32]) -> bool {
+        match self.sort {
+            // The first sync only checks the first page, the next ones stop at the previous one
+            SortType::Recent => self.last_seen.is_none() || galleries.iter().any(|&id| self.is_seen(id)),
+            // A page that was already seen as a whole most likely has nothing new after it either
+            _ => page >= self.pages || (!galleries.is_empty() && galleries.iter().all(|&id| self.is_seen(id))),
+        }
+    }
+
+    /// Records a finished sync of the `new` galleries, of which `failed` must be tried again
+    pub fn synced(&mut self, new: &[u32], failed: &[u32]) {
+        let handled = new.iter().copied().filter(|id| !failed.contains(id));
+        match self.sort {
+            SortType::Recent => {
+                // The failed galleries must stay newer than the last seen one, to be tried again
+                let newest = match failed.iter().min() {
+                    Some(&oldest_failed) => handled.filter(|&id| id < oldest_failed).max(),
+                    None => handled.max(),
+                };
+                self.last_seen = self.last_seen.max(newest);
+            }
+            _ => self.seen.extend(handled),
+        }
+        self.last_sync = SystemTime::now().duration_since(UNIX_EPOCH).ok().map(|d| d.as_secs());
+    }
+}
+
+/// Subscriptions saved in the state file
+#[derive(Serialize, Deserialize, Default)]
+pub struct Subscriptions {
+    subscriptions: Vec<Subscription>,
+    #[serde(skip)]
+    path: PathBuf,
+}
+
+impl Subscriptions {
+    /// Default location of the state file (e.g. `~/.local/share/nhentai-downloader/subscriptions.json`)
+    pub fn default_path() -> Option<PathBuf> {
+        crate::config::data_dir().map(|dir| dir.join("subscriptions.json"))
+    }
+
+    /// Loads the subscriptions saved in `path`, there are none if the file doesn't exist
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Ok(Self { path: path.to_path_buf(), ..Default::default() });
+            }
+            Err(e) => return Err(e).with_context(ctx!("Cannot read subscriptions {path:?}")),
+        };
+
+        let mut subs: Self = serde_json::from_str(&text)
+            .with_context(ctx!("Invalid subscriptions file {path:?}"))?;
+        subs.path = path.to_path_buf();
+        Ok(subs)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(ctx!("Cannot create directory {parent:?}"))?;
+        }
+        let json = serde_json::to_vec_pretty(self)
+            .with_context(ctx!("Cannot serialize subscriptions"))?;
+        std::fs::write(&self.path, json)
+            .with_context(ctx!("Cannot write subscriptions {:?}", self.path))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Subscription> {
+        self.subscriptions.iter()
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut Subscription> {
+        self.subscriptions.iter_mut().find(|s| s.name == name)
+    }
+
+    pub fn add(&mut self, sub: Subscription) -> Result<()> {
+        if self.subscriptions.iter().any(|s| s.name == sub.name) {
+            anyhow::bail!("Subscription `{}` already exists", sub.name);
+        }
+        self.subscriptions.push(sub);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<Subscription> {
+        let index = self.subscriptions.iter().position(|s| s.name == name)
+            .with_context(ctx!("Subscription `{name}` not found"))?;
+        Ok(self.subscriptions.remove(index))
+    }
+}
+
+impl App {
+    pub fn manage_subscriptions(&self, sub: &SubCli) -> Result<()> {
+        let mut subs = sub.state.load()?;
+        match sub.action {
+            SubAction::Add { ref name, ref query, sort, count, ref filters, ref output, ref output_profile, last_seen } => {
+                if last_seen.is_some() && !matches!(sort, SortType::Recent) {
+                    anyhow::bail!("`--last-seen` can only be used with the recent sort");
+                }
+                if count.is_some() && matches!(sort, SortType::Recent) {
+                    anyhow::bail!("`--count` can't be used with the recent sort");
+                }
+                if let Some(ref profile) = *output_profile {
+                    // Fails now if the profile doesn't exist, instead of at the first sync
+                    Config::load(self.args.config.as_deref(), Some(profile))?;
+                }
+                subs.add(Subscription {
+                    name: name.clone(),
+                    query: query.clone(),
+                    sort,
+                    filters: filters.iter().map(Filter::to_string).collect(),
+                    path: output.clone(),
+                    profile: output_profile.clone(),
+                    last_seen,
+                    seen: Default::default(),
+                    pages: count.unwrap_or(NonZeroU32::MIN),
+                    last_sync: None,
+                })?;
+                subs.save()?;
+                log::info!("Added subscription `{name}`");
+            }
+            SubAction::List => {
+                for sub in subs.iter() {
+                    let sort = clap::ValueEnum::to_possible_value(&sub.sort).expect("no skipped sort types");
+                    println!("{}: \"{}\" sorted by {}", sub.name, sub.query, sort.get_name());
+                    for filter in &sub.filters {
+                        println!("  filter: {filter}");
+                    }
+                    if let Some(ref path) = sub.path {
+                        println!("  output: {path:?}");
+                    }
+                    if let Some(ref profile) = sub.profile {
+                        println!("  profile: {profile}");
+                    }
+                    if !matches!(sub.sort, SortType::Recent) {
+                        println!("  pages: {}", sub.pages);
+                    }
+                    match (sub.last_seen, sub.last_sync) {
+                        (Some(id), _) => println!("  last seen: {id}"),
+                        (None, Some(_)) => println!("  galleries seen: {}", sub.seen.len()),
+                        (None, None) => println!("  never synced"),
+                    }
+                }
+            }
+            SubAction::Rm { ref name } => {
+                subs.remove(name)?;
+                subs.save()?;
+                log::info!("Removed subscription `{name}`");
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn sync(&self, sync: &SyncCli) -> Result<()> {
+        let mut subs = sync.state.load()?;
+        let names: Vec<String> = match sync.names.is_empty() {
+            true => subs.iter().map(|s| s.name.clone()).collect(),
+            false => sync.names.clone(),
+        };
+        if names.is_empty() {
+            log::info!("No subscriptions to sync, add one with `sub add`");
+            return Ok(());
+        }
+
+        let mut report = Vec::new();
+        for name in names {
+            let sub = subs.get_mut(&name)
+                .with_context(ctx!("Subscription `{name}` not found"))?;
+            log::info!(">>> Syncing subscription `{name}`");
+            let previous = sub.last_seen;
+            let res = match sub.profile.clone() {
+                Some(ref profile) => match self.with_profile(profile) {
+                    Ok(app) => {
+                        let res = app.sync_subscription(sub).await;
+                        app.save_cookie_jar();
+                        res
+                    }
+                    Err(e) => Err(e),
+                },
+                None => self.sync_subscription(sub).await,
+            };
+            match res {
+                Ok((new, failed)) => {
+                    let seen = match (previous, sub.last_seen) {
+                        (Some(prev), Some(last)) if prev != last => format!(", last seen {prev} -> {last}"),
+                        (None, Some(last)) => format!(", last seen {last}"),
+                        _ => String::new(),
+                    };
+                    report.push(format!("{name}: {new} new galleries, {failed} failed{seen}"));
+                }
+                Err(e) if http::is_blocked(&e) => {
+                    if !self.args.dry_run {
+                        subs.save()?;
+                    }
+                    return Err(e);
+                }
+                Err(e) => {
+                    log::warn!("Failed to sync subscription `{name}`\nError: {e:?}");
+                    self.summary.failed_page();
+                    report.push(format!("{name}: sync failed"));
+                }
+            }
+            if !self.args.dry_run {
+                subs.save()?;
+            }
+        }
+
+        for line in report {
+            println!("{line}");
+        }
+        Ok(())
+    }
+
+    /// Downloads the galleries of a subscription newer than its last seen one, returns the number
+    /// of new and failed galleries
+    async fn sync_subscription(&self, sub: &mut Subscription) -> Result<(usize, usize)> {
+        let filters = sub.filters.iter()
+            .map(|f| f.parse())
+            .collect::<Result<Vec<Filter>>>()
+            .with_context(ctx!("Invalid filter in subscription `{}`", sub.name))?;
+        let out_path = match sub.path {
+            Some(ref path) => path.as_path(),
+            None => self.out_path()?,
+        };
+
+        let listing = Listing::Search { query: sub.query.clone(), sort: sub.sort };
+        let query_res = QueryInfo::load(&self.client, &self.args.network.site_url, listing, NonZeroU32::MIN).await
+            .with_context(ctx!("Failed to load query `{}`", sub.query))?;
+        let (query_info, mut galleries) = match query_res {
+            QueryResult::Gallery(id) => (None, vec![id]),
+            QueryResult::QueryList(i, g) => (Some(i), g),
+        };
+
+        let mut new = Vec::new();
+        let mut page = NonZeroU32::MIN;
+        loop {
+            let last = sub.is_last_page(page, &galleries);
+            for id in galleries {
+                if !sub.is_seen(id) && !new.contains(&id) {
+                    new.push(id);
+                }
+            }
+
+            let Some(ref query_info) = query_info else { break };
+            if last || page >= query_info.pages() {
+                break;
+            }
+            page = page.saturating_add(1);
+            galleries = query_info.load_page(&self.client, page).await?;
+        }
+
+        log::info!("Found {} new galleries for subscription `{}`", new.len(), sub.name);
+
+        let mut failed = Vec::new();
+        let count = new.len();
+        for (i, &id) in new.iter().enumerate() {
+            let res = match self.load_gallery(id).await.inspect_err(|_| self.summary.failed()) {
+                Ok(gallery) => self.download_loaded_gallery_to(&gallery, out_path, &filters, Some((i + 1, count))).await,
+                Err(e) => Err(e),
+            };
+            match res {
+                Err(e) if http::is_blocked(&e) => return Err(e),
+                Err(e) => {
+                    log::warn!("Failed to download gallery: {id}\nError: {e:?}");
+                    failed.push(id);
+                }
+                Ok(()) => {}
+            }
+        }
+
+        sub.synced(&new, &failed);
+        Ok((count, failed.len()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use super::Subscription;
+    use crate::SortType;
+
+    fn subscription(sort: SortType, last_seen: Option<u32>) -> Subscription {
+        Subscription {
+            name: "test".to_string(),
+            query: "artist:someone".to_string(),
+            sort,
+            filters: Vec::new(),
+            path: None,
+            profile: None,
+            last_seen,
+            seen: Default::default(),
+            pages: NonZeroU32::new(3).unwrap(),
+            last_sync: None,
+        }
+    }
+
+    fn page(page: u32) -> NonZeroU32 {
+        NonZeroU32::new(page).unwrap()
+    }
+
+    #[test]
+    fn recent() {
+        let mut sub = subscription(SortType::Recent, Some(100));
+        assert!(sub.is_seen(90));
+        assert!(!sub.is_seen(101));
+
+        // 120 failed, so it stays newer than the last seen one
+        sub.synced(&[130, 120, 110], &[120]);
+        assert_eq!(sub.last_seen, Some(110));
+        assert!(!sub.is_seen(120));
+        assert!(sub.last_sync.is_some());
+
+        sub.synced(&[130, 120], &[]);
+        assert_eq!(sub.last_seen, Some(130));
+        assert!(sub.seen.is_empty());
+    }
+
+    #[test]
+    fn popular() {
+        let mut sub = subscription(SortType::Popular, None);
+        sub.synced(&[500, 20, 300], &[20]);
+        assert!(sub.is_seen(500));
+        assert!(sub.is_seen(300));
+        // An older gallery that climbs into the list later is still new
+        assert!(!sub.is_seen(100));
+        assert!(!sub.is_seen(20));
+        assert_eq!(sub.last_seen, None);
+    }
+
+    #[test]
+    fn last_page() {
+        let mut sub = subscription(SortType::Recent, None);
+        assert!(sub.is_last_page(page(1), &[130, 120]));
+        sub.last_seen = Some(100);
+        assert!(!sub.is_last_page(page(1), &[130, 120]));
+        assert!(sub.is_last_page(page(2), &[110, 100]));
+
+        let mut sub = subscription(SortType::Popular, None);
+        assert!(!sub.is_last_page(page(1), &[500, 20]));
+        assert!(sub.is_last_page(page(3), &[500, 20]));
+        // Stops at a page without anything new, not at one with some new galleries
+        sub.synced(&[500, 20, 300], &[]);
+        assert!(!sub.is_last_page(page(1), &[500, 100]));
+        assert!(sub.is_last_page(page(1), &[300, 20, 500]));
+        assert!(!sub.is_last_page(page(2), &[]));
+    }
+}
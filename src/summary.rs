@@ -0,0 +1,260 @@
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// Exit codes of the process, documented in the README
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Outcome {
+    /// Everything was downloaded
+    Success = 0,
+    /// The run stopped because of an error, or no gallery could be downloaded
+    Failure = 1,
+    /// Invalid command line, config file or other input, nothing was done
+    Config = 2,
+    /// Some galleries or pages failed
+    Partial = 3,
+}
+
+impl From<Outcome> for ExitCode {
+    fn from(outcome: Outcome) -> Self {
+        ExitCode::from(outcome as u8)
+    }
+}
+
+/// Counters of what happened during a run, reported at the end
+#[derive(Default)]
+pub struct Summary {
+    downloaded: AtomicUsize,
+    /// Galleries with all the pages already present
+    present: AtomicUsize,
+    /// Galleries with some failed pages
+    partial: AtomicUsize,
+    failed: AtomicUsize,
+    filtered: AtomicUsize,
+    blacklisted: AtomicUsize,
+    /// Galleries skipped because they were already handled earlier in the run
+    duplicates: AtomicUsize,
+    /// Listing pages that couldn't be loaded, with the galleries on them
+    failed_pages: AtomicUsize,
+    /// Pages fetched from the image servers, and their size
+    pages: AtomicUsize,
+    bytes: AtomicU64,
+    /// Galleries that would be downloaded by a dry run
+    planned: AtomicUsize,
+    planned_pages: AtomicUsize,
+    planned_bytes: AtomicU64,
+    /// Galleries of a dry run whose size couldn't be estimated
+    unknown_size: AtomicUsize,
+}
+
+/// Values of the main counters at some point of the run
+#[derive(Clone, Copy, Default)]
+pub struct Counts {
+    pub downloaded: usize,
+    pub present: usize,
+    pub partial: usize,
+    pub failed: usize,
+    pub filtered: usize,
+    pub blacklisted: usize,
+    pub duplicates: usize,
+    pub failed_pages: usize,
+}
+
+impl Counts {
+    /// What was counted after `before`
+    pub fn since(self, before: Self) -> Self {
+        Self {
+            downloaded: self.downloaded - before.downloaded,
+            present: self.present - before.present,
+            partial: self.partial - before.partial,
+            failed: self.failed - before.failed,
+            filtered: self.filtered - before.filtered,
+            blacklisted: self.blacklisted - before.blacklisted,
+            duplicates: self.duplicates - before.duplicates,
+            failed_pages: self.failed_pages - before.failed_pages,
+        }
+    }
+}
+
+impl std::fmt::Display for Counts {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { downloaded, present, partial, failed, filtered, blacklisted, duplicates, failed_pages } = self;
+        write!(f, "{downloaded} galleries downloaded")?;
+        if *present > 0 {
+            write!(f, ", {present} already present")?;
+        }
+        if *partial > 0 {
+            write!(f, ", {partial} partially failed")?;
+        }
+        write!(f, ", {failed} failed, {filtered} skipped by filters, {blacklisted} blacklisted")?;
+        if *duplicates > 0 {
+            write!(f, ", {duplicates} duplicates")?;
+        }
+        if *failed_pages > 0 {
+            write!(f, ", {failed_pages} listing pages failed")?;
+        }
+        Ok(())
+    }
+}
+
+/// Formats a byte count with binary units (e.g. `12.3 MiB`)
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{bytes} B"),
+        _ => format!("{size:.1} {}", UNITS[unit]),
+    }
+}
+
+impl Summary {
+    pub fn downloaded(&self) {
+        self.downloaded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn present(&self) {
+        self.present.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn partial(&self) {
+        self.partial.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts pages fetched from the image servers
+    pub fn transferred(&self, pages: usize, bytes: u64) {
+        self.pages.fetch_add(pages, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn failed(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn filtered(&self) {
+        self.filtered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn blacklisted(&self) {
+        self.blacklisted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn duplicate(&self) {
+        self.duplicates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a listing page that couldn't be loaded
+    pub fn failed_page(&self) {
+        self.failed_pages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn counts(&self) -> Counts {
+        Counts {
+            downloaded: self.downloaded.load(Ordering::Relaxed),
+            present: self.present.load(Ordering::Relaxed),
+            partial: self.partial.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            filtered: self.filtered.load(Ordering::Relaxed),
+            blacklisted: self.blacklisted.load(Ordering::Relaxed),
+            duplicates: self.duplicates.load(Ordering::Relaxed),
+            failed_pages: self.failed_pages.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Counts a gallery that a dry run would download, `bytes` is `None` if the size is unknown
+    pub fn planned(&self, pages: usize, bytes: Option<u64>) {
+        self.planned.fetch_add(1, Ordering::Relaxed);
+        self.planned_pages.fetch_add(pages, Ordering::Relaxed);
+        match bytes {
+            Some(bytes) => { self.planned_bytes.fetch_add(bytes, Ordering::Relaxed); }
+            None => { self.unknown_size.fetch_add(1, Ordering::Relaxed); }
+        }
+    }
+
+    /// Outcome of a run that finished without errors, the galleries planned by a dry run count as
+    /// downloaded
+    pub fn outcome(&self) -> Outcome {
+        let Counts { downloaded, present, partial, failed, failed_pages, .. } = self.counts();
+        let planned = self.planned.load(Ordering::Relaxed);
+        match (downloaded + present + partial + planned, partial + failed + failed_pages) {
+            (_, 0) => Outcome::Success,
+            (0, _) => Outcome::Failure,
+            _ => Outcome::Partial,
+        }
+    }
+
+    pub fn log(&self) {
+        let counts = self.counts();
+        let Counts { downloaded, present, partial, failed, filtered, blacklisted, duplicates, failed_pages } = counts;
+        let planned = self.planned.load(Ordering::Relaxed);
+        if downloaded + present + partial + failed + filtered + blacklisted + duplicates + failed_pages + planned == 0 {
+            return;
+        }
+
+        if planned > 0 {
+            let pages = self.planned_pages.load(Ordering::Relaxed);
+            let bytes = format_size(self.planned_bytes.load(Ordering::Relaxed));
+            let unknown = match self.unknown_size.load(Ordering::Relaxed) {
+                0 => String::new(),
+                n => format!(" ({n} galleries of unknown size)"),
+            };
+            let failed_pages = match failed_pages {
+                0 => String::new(),
+                n => format!(", {n} listing pages failed"),
+            };
+            log::info!(
+                "Dry run: {planned} galleries to download, {pages} pages, ~{bytes}{unknown}, {failed} failed, {filtered} skipped by filters, {blacklisted} blacklisted{failed_pages}"
+            );
+            return;
+        }
+        let pages = self.pages.load(Ordering::Relaxed);
+        let bytes = format_size(self.bytes.load(Ordering::Relaxed));
+        log::info!("Summary: {counts}, {pages} pages ({bytes}) transferred");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Outcome, Summary};
+
+    #[test]
+    fn outcome() {
+        let summary = Summary::default();
+        assert_eq!(summary.outcome(), Outcome::Success);
+        summary.filtered();
+        summary.present();
+        assert_eq!(summary.outcome(), Outcome::Success);
+        summary.downloaded();
+        summary.failed();
+        assert_eq!(summary.outcome(), Outcome::Partial);
+
+        // Some pages of a gallery failed, but the rest was downloaded
+        let summary = Summary::default();
+        summary.partial();
+        assert_eq!(summary.outcome(), Outcome::Partial);
+
+        let summary = Summary::default();
+        summary.failed();
+        summary.failed_page();
+        assert_eq!(summary.outcome(), Outcome::Failure);
+        summary.downloaded();
+        assert_eq!(summary.outcome(), Outcome::Partial);
+    }
+
+    #[test]
+    fn dry_run_outcome() {
+        let summary = Summary::default();
+        summary.planned(20, Some(1 << 20));
+        summary.planned(10, None);
+        assert_eq!(summary.outcome(), Outcome::Success);
+        summary.failed_page();
+        assert_eq!(summary.outcome(), Outcome::Partial);
+
+        let summary = Summary::default();
+        summary.failed();
+        assert_eq!(summary.outcome(), Outcome::Failure);
+    }
+}
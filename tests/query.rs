@@ -0,0 +1,117 @@
+//! Runs a query end to end against a local server that stands in for the site and the image
+//! servers, serving the pages in `tests/fixtures`
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+/// Answers a request for `path`, with the status and body
+fn respond(path: &str) -> (u16, Vec<u8>) {
+    let fixture = |name: &str| std::fs::read(Path::new(FIXTURES).join(name)).unwrap();
+    if let Some(query) = path.strip_prefix("/search/?") {
+        return match query.split('&').find_map(|pair| pair.strip_prefix("page=")) {
+            Some("1") => (200, fixture("search/page1.html")),
+            Some("2") => (200, fixture("search/page2.html")),
+            _ => (404, Vec::new()),
+        };
+    }
+    match path {
+        "/g/421337/" => (200, fixture("gallery/escapes.html")),
+        "/g/398120/" => (200, fixture("gallery/emoji.html")),
+        // Each page holds its own path, to check that it ended up in the right file
+        path if path.starts_with("/galleries/") => (200, path.as_bytes().to_vec()),
+        _ => (404, Vec::new()),
+    }
+}
+
+fn handle(mut stream: TcpStream) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request = String::new();
+    reader.read_line(&mut request).unwrap();
+    // The headers are not needed, but must be read before answering
+    let mut line = String::new();
+    while reader.read_line(&mut line).unwrap() > 2 {
+        line.clear();
+    }
+
+    let path = request.split(' ').nth(1).unwrap_or("/");
+    let (status, body) = respond(path);
+    let head = format!("HTTP/1.1 {status} -\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+    stream.write_all(head.as_bytes()).unwrap();
+    if !request.starts_with("HEAD ") {
+        stream.write_all(&body).unwrap();
+    }
+}
+
+/// Starts the server, returns its url
+fn serve() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            std::thread::spawn(move || handle(stream.unwrap()));
+        }
+    });
+    url
+}
+
+/// Empty directory for the output of a test
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("nhentai-downloader-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn run(site: &str, dir: &Path, args: &[&str]) -> Output {
+    // An empty config file, so that the one of the user doesn't change the options
+    let config = dir.join("config.toml");
+    std::fs::write(&config, "").unwrap();
+
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_nhentai-downloader"));
+    for (name, _) in std::env::vars_os() {
+        if name.to_string_lossy().starts_with("NHENTAI_") {
+            cmd.env_remove(name);
+        }
+    }
+    cmd.arg("--config").arg(&config)
+        .args(["--site-url", site, "--image-url", site])
+        .arg("--path").arg(dir.join("out"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn query() {
+    let site = serve();
+    let dir = temp_dir("query");
+
+    let output = run(&site, &dir, &["query", "full color", "--count", "0"]);
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{log}");
+    assert!(log.contains("2 galleries downloaded"), "{log}");
+
+    let out = dir.join("out");
+    let page = std::fs::read_to_string(out.join("421337/3.png")).unwrap();
+    assert_eq!(page, "/galleries/2290118/3.png");
+    for page in ["1.jpg", "2.jpg", "3.png", "4.webp", "gallery.json", "state.json"] {
+        assert!(out.join("421337").join(page).is_file(), "missing 421337/{page}");
+    }
+    for page in ["1.webp", "2.webp", "3.webp", "gallery.json", "state.json"] {
+        assert!(out.join("398120").join(page).is_file(), "missing 398120/{page}");
+    }
+    let state = std::fs::read_to_string(out.join("398120/state.json")).unwrap();
+    assert!(state.contains("\"complete\""), "{state}");
+
+    // Nothing is downloaded again
+    let output = run(&site, &dir, &["query", "full color", "--count", "0"]);
+    let log = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{log}");
+    assert!(log.contains("0 galleries downloaded, 2 already present"), "{log}");
+
+    let _ = std::fs::remove_dir_all(&dir);
+}